@@ -0,0 +1,25 @@
+use pc_mqtt_rs::*;
+use std::env;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Vehicle IDs are given as arguments, otherwise two default ones are simulated
+    let mut vehicle_list: Vec<String> = env::args().skip(1).collect();
+    if vehicle_list.is_empty() {
+        vehicle_list = vec![String::from("d98ebab7c206"), String::from("f4c22c6c0382")];
+    }
+
+    // Same broker and topic settings as the controllers, see BrokerConfig::from_env and Namespace::from_env.
+    let broker = BrokerConfig::from_env()?;
+    let namespace = Namespace::from_env();
+
+    let layout = match env::var("PC_SIM_LAYOUT") {
+        Ok(path) => TrackLayout::from_file(path)?,
+        Err(_) => TrackLayout::default(),
+    };
+
+    let simulator = Simulator::new(&vehicle_list, layout)?.run(&broker, &namespace)?;
+    println!("Simulating {} vehicles", vehicle_list.len());
+
+    simulator.join().expect("simulator thread panicked");
+    Ok(())
+}
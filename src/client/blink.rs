@@ -1,41 +1,204 @@
-//! The blink module is the simplest of the three steering controllers. When run it will toggle the lights of all vehicles in the vehicle list every second. The current state of the lights are stored in the state field.
-use crate::library::{mqtt::Mqtt, payload::Payload, topic::Topic};
-use std::{thread, time::Duration};
+//! The blink module is the simplest of the three steering controllers. When run it will toggle the lights of all vehicles in the vehicle list every second, or every configured interval.
+//!
+//! The lights can also follow a LightPattern, with every other vehicle out of step if alternate is set, and other patterns used while a vehicle is inside a slow zone or stopped by the fleet, its own or one of its groups emergency stop. The last lights sent to each vehicle are stored in the sent field, so only changes are published.
+use crate::library::{
+    config::BrokerConfig,
+    controller::{self, try_publish, Context, Controller, RESOLUTION},
+    emergency::EmergencyState,
+    error::Error,
+    handle::ControllerHandle,
+    payload::Payload,
+    program::LightPattern,
+    topic::{Namespace, Topic},
+};
+use rumqttc::Publish;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-/// Holds the current state and a list of vehicles.
+/// Holds the patterns, a list of vehicles and their current state.
+#[derive(Clone)]
 pub struct Blink {
-    /// Current state of the lights.
-    state: bool,
     /// A list of vehicles IDs.
     vehicles: Vec<String>,
+    /// Time between two toggles, 1 second by default. Only used without pattern.
+    interval: Duration,
+    pattern: Option<LightPattern>,
+    /// Whether every other vehicle is shifted by half a period.
+    alternate: bool,
+    /// Pattern of the vehicles inside a slow zone.
+    zone_pattern: Option<LightPattern>,
+    /// Pattern of the vehicles stopped by an emergency, of the fleet, of the vehicle or of one of its groups.
+    emergency_pattern: Option<LightPattern>,
+    emergency: EmergencyState,
+    slow_vehicles: Vec<String>,
+    /// When the patterns started, set on the first tick.
+    started: Option<Instant>,
+    /// Last front and back lights sent to each vehicle.
+    sent: HashMap<String, (bool, bool)>,
 }
 
 impl Blink {
     /// Returns a new instance of Blink.
     pub fn new(vehicle_list: &[String]) -> Self {
         Blink {
-            state: false,
             vehicles: vehicle_list.to_owned(),
+            interval: Duration::from_secs(1),
+            pattern: None,
+            alternate: false,
+            zone_pattern: None,
+            emergency_pattern: None,
+            emergency: EmergencyState::default(),
+            slow_vehicles: Vec::new(),
+            started: None,
+            sent: HashMap::new(),
         }
     }
 
-    /// Runs the client in a separate thread, consuming it.
+    /// Sets the time between two toggles.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the pattern followed by every vehicle, instead of toggling both lights every interval.
+    pub fn with_pattern(mut self, pattern: LightPattern) -> Self {
+        self.pattern = Some(pattern);
+        self
+    }
+
+    /// Shifts the pattern of every other vehicle by half a period.
+    pub fn with_alternate(mut self, alternate: bool) -> Self {
+        self.alternate = alternate;
+        self
+    }
+
+    /// Sets the pattern of the vehicles inside a slow zone.
+    pub fn with_zone_pattern(mut self, pattern: LightPattern) -> Self {
+        self.zone_pattern = Some(pattern);
+        self
+    }
+
+    /// Sets the pattern of the vehicles stopped by an emergency, of the fleet, of the vehicle or of one of its groups.
+    pub fn with_emergency_pattern(mut self, pattern: LightPattern) -> Self {
+        self.emergency_pattern = Some(pattern);
+        self
+    }
+
+    /// Names a group of vehicles, which show the emergency pattern when stopped on its group emergency topic.
+    pub fn with_group(mut self, name: &str, vehicles: &[String]) -> Self {
+        self.emergency.add_group(name, vehicles);
+        self
+    }
+
+    /// Runs the client in a separate thread until stopped, consuming it.
     ///
-    /// Returns a handle to the thread.
-    pub fn run(mut self) -> thread::JoinHandle<()> {
-        let (mut client, connection) = Mqtt::new("groupg_blink");
-        let _rx = connection.start_loop();
-
-        thread::spawn(move || loop {
-            self.state = !self.state;
-
-            for vehicle in &self.vehicles {
-                client.publish(
-                    &Topic::Relay(&Topic::VehicleI(vehicle).get()).get(),
-                    &Payload::Lights(self.state, self.state).get(),
-                );
+    /// Returns a handle to the thread, or an error if the MQTT client couldn't be created or a pattern is invalid.
+    pub fn run(
+        self,
+        config: &BrokerConfig,
+        namespace: &Namespace,
+    ) -> Result<ControllerHandle, Error> {
+        controller::spawn(self, config, namespace)
+    }
+
+    /// Returns the pattern a vehicle follows right now.
+    fn pattern_of(&self, vehicle: &String) -> LightPattern {
+        let emergency = self
+            .emergency_pattern
+            .filter(|_| self.emergency.is_stopped(vehicle));
+        let zone = self
+            .zone_pattern
+            .filter(|_| self.slow_vehicles.contains(vehicle));
+        emergency
+            .or(zone)
+            .or(self.pattern)
+            .unwrap_or_else(|| LightPattern::blink(self.interval * 2))
+    }
+}
+
+impl Controller for Blink {
+    fn name(&self) -> &str {
+        "blink"
+    }
+
+    fn steering(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.pattern.is_none() && self.interval.is_zero() {
+            return Err(Error::InvalidConfig(String::from("interval is zero")));
+        }
+        for (name, pattern) in [
+            ("pattern", self.pattern),
+            ("zone pattern", self.zone_pattern),
+            ("emergency pattern", self.emergency_pattern),
+        ] {
+            if let Some(pattern) = pattern {
+                pattern
+                    .validate()
+                    .map_err(|e| Error::InvalidConfig(format!("{}: {}", name, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The emergency and zone topics, if they have a pattern.
+    fn subscriptions(&self, ns: &Namespace) -> Vec<String> {
+        let mut topics = Vec::new();
+        if self.emergency_pattern.is_some() {
+            topics.extend(EmergencyState::topics(ns));
+        }
+        if self.zone_pattern.is_some() {
+            topics.push(Topic::Zone.get(ns));
+        }
+        topics
+    }
+
+    /// Updates the emergency state or the slow vehicles, the lights being updated on the next tick.
+    fn on_message(&mut self, ctx: &mut Context, message: &Publish) -> Result<(), Error> {
+        let topic = Topic::parse(&message.topic, ctx.namespace);
+        match (topic, Payload::parse(&message.payload)) {
+            (Some(Topic::Zone), Ok(Payload::Zone200(vehicles))) => {
+                self.slow_vehicles = vehicles;
+            }
+            (Some(topic), Ok(Payload::Emergency(value))) => {
+                self.emergency.update(topic, value);
+            }
+            (_, other) => {
+                dbg!(&message.topic, &other);
             }
-            thread::sleep(Duration::from_secs(1));
-        })
+        }
+        Ok(())
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.interval.min(RESOLUTION))
+    }
+
+    /// Sends the lights of the vehicles whose lights changed.
+    fn on_tick(&mut self, ctx: &mut Context) -> Result<(), Error> {
+        let elapsed = self.started.get_or_insert_with(Instant::now).elapsed();
+
+        for (i, vehicle) in self.vehicles.iter().enumerate() {
+            let (front, back) = self
+                .pattern_of(vehicle)
+                .lights(elapsed, self.alternate && i % 2 == 1);
+            if self.sent.get(vehicle) == Some(&(front, back)) {
+                continue;
+            }
+
+            // A dropped message isn't recorded as sent, so it is tried again on the next tick
+            if try_publish(
+                ctx.client,
+                &Topic::Relay(&Topic::VehicleI(vehicle).get(ctx.namespace)).get(ctx.namespace),
+                &Payload::Lights(front, back).get_from(ctx.client_id),
+            )? {
+                self.sent.insert(vehicle.clone(), (front, back));
+            }
+        }
+        Ok(())
     }
 }
@@ -1,13 +1,38 @@
 //! This lane module is part of the steering controller.
 //!
 //! This module lane contains the Lane struct and its implementation.
-use crate::library::{mqtt::Mqtt, payload::Payload, topic::Topic};
-use std::{thread, time::Duration};
+//!
+//! Every vehicle follows a LaneSchedule. Vehicles without a schedule of their own change to each offset in turn every interval.
+use crate::library::{
+    config::BrokerConfig,
+    controller::{self, Context, Controller, RESOLUTION},
+    error::Error,
+    handle::ControllerHandle,
+    payload::{Payload, TrackEvent},
+    program::{LaneSchedule, LaneStep},
+    topic::{Namespace, Topic},
+};
+use rumqttc::Publish;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 /// Struct holding the offsets and a list of vehicles.
+#[derive(Clone)]
 pub struct Lane {
     vehicles: Vec<String>,
     offsets: Vec<i16>,
+    /// Time between two lane changes, 5 seconds by default.
+    interval: Duration,
+    /// Sideways velocity of a lane change, 200 by default.
+    velocity: u16,
+    /// Sideways acceleration of a lane change, 500 by default.
+    acceleration: u16,
+    /// Schedules of the vehicles that don't follow the offsets.
+    schedules: HashMap<String, LaneSchedule>,
+    /// Where each vehicle is in its schedule, set on the first tick.
+    progress: HashMap<String, Progress>,
 }
 
 impl Lane {
@@ -16,30 +41,214 @@ impl Lane {
         Lane {
             offsets: offsets.to_owned(),
             vehicles: vehicles.to_owned(),
+            interval: Duration::from_secs(5),
+            velocity: 200,
+            acceleration: 500,
+            schedules: HashMap::new(),
+            progress: HashMap::new(),
         }
     }
 
+    /// Sets the time between two lane changes.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the sideways velocity and acceleration of the lane changes.
+    pub fn with_lane_change(mut self, velocity: u16, acceleration: u16) -> Self {
+        self.velocity = velocity;
+        self.acceleration = acceleration;
+        self
+    }
+
+    /// Makes a vehicle follow its own schedule instead of the offsets.
+    pub fn with_schedule(mut self, vehicle: &str, schedule: LaneSchedule) -> Self {
+        self.schedules.insert(vehicle.to_string(), schedule);
+        self
+    }
+
     /// Main logic of the lane client.
     ///
-    /// Runs an infinite loop in a new thread, consuming the self and returning a handle to the thread.
+    /// Runs the client in a new thread until stopped, consuming the self and returning a handle to the thread.
     ///
-    /// The loop publishes different offsets in lane message every 5 seconds for each vehicle in @vehicle_list.
-    pub fn run(self) -> thread::JoinHandle<()> {
-        let (mut client, connection) = Mqtt::new("groupg_lane");
-        let _rx = connection.start_loop();
-
-        thread::spawn(move || {
-            let mut i = 0;
-            loop {
-                for vehicle in &self.vehicles {
-                    client.publish(
-                        &Topic::Relay(&Topic::VehicleI(vehicle).get()).get(),
-                        &Payload::Lane(self.offsets[i], 200, 500).get(), //&Payload::Lane(0, 200, 500).get() // for testing
-                    );
-                }
-                i = (i + 1) % self.offsets.len();
-                thread::sleep(Duration::from_secs(5));
-            }
+    /// The client publishes the lane changes of each vehicle's schedule in lane messages. Without schedules, it publishes different offsets every interval (5 seconds by default) for each vehicle in @vehicle_list.
+    ///
+    /// Fails with Error::InvalidConfig if the offset list is empty while a vehicle has no schedule, or if a schedule is invalid.
+    pub fn run(
+        self,
+        config: &BrokerConfig,
+        namespace: &Namespace,
+    ) -> Result<ControllerHandle, Error> {
+        controller::spawn(self, config, namespace)
+    }
+
+    /// Returns the progress of a vehicle, starting its schedule if needed.
+    fn progress(&mut self, vehicle: &str, now: Instant) -> &mut Progress {
+        let schedule = &self.schedules;
+        let default = || {
+            LaneSchedule::cycle(
+                &self.offsets,
+                self.interval,
+                self.velocity,
+                self.acceleration,
+            )
+        };
+        self.progress.entry(vehicle.to_string()).or_insert_with(|| {
+            Progress::new(schedule.get(vehicle).cloned().unwrap_or_else(default), now)
         })
     }
 }
+
+impl Controller for Lane {
+    fn name(&self) -> &str {
+        "lane"
+    }
+
+    fn steering(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let all_scheduled = self
+            .vehicles
+            .iter()
+            .all(|vehicle| self.schedules.contains_key(vehicle));
+        if !all_scheduled {
+            if self.offsets.is_empty() {
+                return Err(Error::InvalidConfig(String::from("offset list is empty")));
+            }
+            if self.interval.is_zero() {
+                return Err(Error::InvalidConfig(String::from("interval is zero")));
+            }
+        }
+        for (vehicle, schedule) in &self.schedules {
+            if !self.vehicles.contains(vehicle) {
+                return Err(Error::InvalidConfig(format!(
+                    "schedule for unknown vehicle {}",
+                    vehicle
+                )));
+            }
+            schedule
+                .validate()
+                .map_err(|e| Error::InvalidConfig(format!("schedule of {}: {}", vehicle, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Track events of the vehicles whose schedule waits for track pieces.
+    fn subscriptions(&self, ns: &Namespace) -> Vec<String> {
+        self.schedules
+            .iter()
+            .filter(|(_, schedule)| schedule.uses_tracks())
+            .map(|(vehicle, _)| Topic::VehicleE(vehicle, "track").get(ns))
+            .collect()
+    }
+
+    /// Sends the lane changes waiting for the vehicle to enter the track piece.
+    fn on_message(&mut self, ctx: &mut Context, message: &Publish) -> Result<(), Error> {
+        let vehicle = match Topic::parse(&message.topic, ctx.namespace) {
+            Some(Topic::VehicleE(vehicle, "track")) => vehicle.to_string(),
+            _ => {
+                dbg!("unexpected topic", &message.topic);
+                return Ok(());
+            }
+        };
+        let track_id = match serde_json::from_slice::<TrackEvent>(&message.payload) {
+            Ok(event) => event.track_id,
+            Err(e) => {
+                dbg!(e);
+                return Ok(());
+            }
+        };
+
+        let step = self.progress(&vehicle, Instant::now()).enter(track_id);
+        match step {
+            Some(step) => publish(ctx, &vehicle, &step),
+            None => Ok(()),
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.interval.min(RESOLUTION))
+    }
+
+    /// Sends the timed lane changes that are due.
+    fn on_tick(&mut self, ctx: &mut Context) -> Result<(), Error> {
+        let now = Instant::now();
+        for vehicle in self.vehicles.clone() {
+            if let Some(step) = self.progress(&vehicle, now).tick(now) {
+                publish(ctx, &vehicle, &step)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Publishes a lane change, only failing if the client can't be used anymore.
+fn publish(ctx: &mut Context, vehicle: &str, step: &LaneStep) -> Result<(), Error> {
+    controller::publish(
+        ctx.client,
+        &Topic::Relay(&Topic::VehicleI(vehicle).get(ctx.namespace)).get(ctx.namespace),
+        &Payload::Lane(step.offset, step.velocity, step.acceleration).get_from(ctx.client_id),
+    )
+}
+
+/// Position of a vehicle in its schedule.
+#[derive(Clone)]
+struct Progress {
+    schedule: LaneSchedule,
+    step: usize,
+    /// When the current step can be sent.
+    due: Instant,
+    /// Last track piece entered.
+    track_id: Option<u64>,
+    finished: bool,
+}
+
+impl Progress {
+    fn new(schedule: LaneSchedule, now: Instant) -> Self {
+        let due = now + schedule.phase();
+        Progress {
+            schedule,
+            step: 0,
+            due,
+            track_id: None,
+            finished: false,
+        }
+    }
+
+    /// Returns the current step if it is timed and due, moving to the next one.
+    fn tick(&mut self, now: Instant) -> Option<LaneStep> {
+        let step = &self.schedule.steps[self.step];
+        if self.finished || now < self.due || step.on_track.is_some() {
+            return None;
+        }
+        Some(self.advance(now))
+    }
+
+    /// Records the track piece entered, returning the current step if it was waiting for it.
+    fn enter(&mut self, track_id: u64) -> Option<LaneStep> {
+        let entered = self.track_id != Some(track_id);
+        self.track_id = Some(track_id);
+
+        let now = Instant::now();
+        let step = &self.schedule.steps[self.step];
+        if self.finished || !entered || now < self.due || step.on_track != Some(track_id) {
+            return None;
+        }
+        Some(self.advance(now))
+    }
+
+    /// Moves to the next step, returning the one to send.
+    fn advance(&mut self, now: Instant) -> LaneStep {
+        let step = self.schedule.steps[self.step].clone();
+        self.due = now + step.duration();
+        self.step += 1;
+        if self.step == self.schedule.steps.len() {
+            self.step = 0;
+            self.finished = !self.schedule.repeat;
+        }
+        step
+    }
+}
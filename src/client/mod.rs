@@ -1,5 +1,6 @@
 pub mod blink;
 pub mod lane;
 pub mod relay;
+pub mod simulator;
 pub mod speed;
 pub mod track;
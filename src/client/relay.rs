@@ -1,17 +1,48 @@
 //! This module contains the Relay struct and its methods. It is responsible for relaying messages, as well as handling emergency and speed limit states.
 
-use crate::library::{mqtt::Mqtt, payload::Payload, topic::Topic};
+use crate::library::{
+    config::BrokerConfig,
+    controller::{self, publish, Context, Controller},
+    emergency::EmergencyState,
+    error::Error,
+    handle::ControllerHandle,
+    mqtt::{ClientWrapper, LastWill},
+    payload::{Payload, Rejection},
+    policy::{Decision, Facts, Policy},
+    topic::{Namespace, Topic},
+};
+use rumqttc::Publish;
 use serde_json;
-use std::thread::{self};
+use std::collections::BTreeMap;
 
-/// The Relay struct holds a list of vehicle IDs, the emergency state, a list of vehicles inside a slow zone, and the last speed value.
+/// Reason given when a message is relayed to a topic that doesn't take commands.
+const NOT_A_COMMAND_TOPIC: &str = "not a vehicle or host command topic";
+
+/// Speed and lane last requested for a vehicle through the relay, before the policy is applied.
+#[derive(Debug, Clone, Copy, Default)]
+struct Requested {
+    /// Velocity and acceleration.
+    speed: Option<(i16, u16)>,
+    /// Offset, velocity and acceleration.
+    lane: Option<(i16, u16, u16)>,
+}
+
+/// The Relay struct holds a list of vehicle IDs, the emergency states, a list of vehicles inside a slow zone, the speed and lane last requested for each vehicle, the last lights of each vehicle and the policy applied to relayed messages.
 ///
-/// Everything except the vehicle list is updated by incoming messages.
+/// Everything except the vehicle list and the groups is updated by incoming messages.
+#[derive(Clone)]
 pub struct Relay {
     vehicle_list: Vec<String>,
-    emergency: bool,
+    emergency: EmergencyState,
     inside_slow_zone: Vec<String>,
-    last_speed: i64,
+    requested: BTreeMap<String, Requested>,
+    /// Last lights relayed to each vehicle.
+    lights: BTreeMap<String, (bool, bool)>,
+    policy: Policy,
+    /// Client IDs allowed to send commands, None if commands don't need a sender.
+    senders: Option<Vec<String>>,
+    /// Whether commands from unknown senders are dropped without a rejection.
+    ignore_unknown: bool,
 }
 
 impl Relay {
@@ -19,128 +50,319 @@ impl Relay {
     pub fn new(vehicle_list: &[String]) -> Relay {
         Relay {
             vehicle_list: vehicle_list.to_owned(),
-            emergency: false,
+            emergency: EmergencyState::default(),
             inside_slow_zone: Vec::new(),
-            last_speed: 0,
+            requested: BTreeMap::new(),
+            lights: BTreeMap::new(),
+            policy: Policy::default(),
+            senders: None,
+            ignore_unknown: false,
+        }
+    }
+
+    /// Only relays commands whose sender is one of these client IDs, see Payload::get_from.
+    ///
+    /// Commands without a sender or from another client are rejected, or ignored if ignore_unknown is true.
+    pub fn with_senders(mut self, senders: &[String], ignore_unknown: bool) -> Self {
+        self.senders = Some(senders.to_owned());
+        self.ignore_unknown = ignore_unknown;
+        self
+    }
+
+    /// Replaces the default policy (emergency and speed limit zone rules) applied to relayed messages.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Names a group of vehicles, which can then be stopped and released together on its group emergency topic.
+    pub fn with_group(mut self, name: &str, vehicles: &[String]) -> Self {
+        self.emergency.add_group(name, vehicles);
+        self
+    }
+
+    /// Returns what the policy needs to know about a vehicle, or about the fleet if there is none.
+    fn facts<'v>(&self, vehicle: Option<&'v str>) -> Facts<'v> {
+        Facts {
+            vehicle,
+            stopped: vehicle.map_or(self.emergency.fleet, |id| self.emergency.is_stopped(id)),
+            in_slow_zone: vehicle.is_some_and(|id| self.inside_slow_zone.iter().any(|v| v == id)),
+            lights: vehicle.and_then(|id| self.lights.get(id).copied()),
         }
     }
 
-    /// Handles all incoming messages and relays them to the correct recipient.
+    /// Sends a vehicle the speed it last requested through the policy, and its last requested lane as well if with_lane is true.
+    ///
+    /// Used when a vehicle enters or leaves a slow zone (speed only) and when it is released from an emergency stop. Nothing is sent for a speed or lane the vehicle never requested, the relay not knowing what it should be.
+    fn restore(&self, ctx: &mut Context, vehicle: &str, with_lane: bool) -> Result<(), Error> {
+        let requested = self.requested.get(vehicle).copied().unwrap_or_default();
+        if let Some((velocity, acceleration)) = requested.speed {
+            self.send(ctx, vehicle, Payload::Speed(velocity, acceleration))?;
+        }
+        match requested.lane {
+            Some((offset, velocity, acceleration)) if with_lane => {
+                self.send(ctx, vehicle, Payload::Lane(offset, velocity, acceleration))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends a payload to a vehicle through the policy.
+    fn send(&self, ctx: &mut Context, vehicle: &str, payload: Payload) -> Result<(), Error> {
+        let (decision, rule) = self.policy.evaluate(&payload, &self.facts(Some(vehicle)));
+        let topic = Topic::VehicleI(vehicle).get(ctx.namespace);
+        self.report(ctx, &topic, &decision, rule)?;
+        match decision {
+            Decision::Pass => publish(ctx.client, &topic, &payload.get()),
+            Decision::Rewrite(payload) => publish(ctx.client, &topic, &payload.get()),
+            Decision::Drop => Ok(()),
+        }
+    }
+
+    /// Publishes the report of a decision on the Decisions topic, if the policy reports it.
+    fn report(
+        &self,
+        ctx: &mut Context,
+        topic: &str,
+        decision: &Decision,
+        rule: Option<&str>,
+    ) -> Result<(), Error> {
+        match self.policy.report(topic, decision, rule) {
+            Some(report) => publish(
+                ctx.client,
+                &Topic::Decisions.get(ctx.namespace),
+                &serde_json::to_string(&report).expect("should be Ok(String)"),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Run the client and return it's thread handle.
+    ///
+    /// The relay registers a retained Emergency(true) message as its Last Will, so if it dies without disconnecting (stopping it through its handle disconnects it), any other relay listening on the emergency topic stops the vehicles, and a restarted relay starts in the emergency state until it is released.
+    pub fn run(
+        self,
+        config: &BrokerConfig,
+        namespace: &Namespace,
+    ) -> Result<ControllerHandle, Error> {
+        controller::spawn(self, config, namespace)
+    }
+}
+
+impl Controller for Relay {
+    fn name(&self) -> &str {
+        "relay"
+    }
+
+    fn last_will(&self, ns: &Namespace) -> Option<LastWill> {
+        Some(LastWill {
+            topic: Topic::Emergency.get(ns),
+            payload: Payload::Emergency(true).get(),
+            retain: true,
+        })
+    }
+
+    fn subscriptions(&self, ns: &Namespace) -> Vec<String> {
+        vec![
+            Topic::Relay("#").get(ns),
+            Topic::Emergency.get(ns),
+            Topic::VehicleEmergency("+").get(ns),
+            Topic::GroupEmergency("+").get(ns),
+            Topic::Zone.get(ns),
+        ]
+    }
+
+    /// Handles an incoming message and relays it to the correct recipient.
     ///
-    /// This method is called in a loop, hence the name.
+    /// Messages are routed by their parsed topic, which is either Emergency ("GroupG/Emergency/I"), VehicleEmergency ("GroupG/Emergency/Vehicles/<id>/I"), GroupEmergency ("GroupG/Emergency/Groups/<name>/I"), Zone ("GroupG/Zone/I") or Relay ("GroupG/Relay/") messages.
     ///
-    /// The first thing it does is to create its own MQTT client and subscribe to the correct topics.
+    /// Emergency and Zone messages are handled by updating the state of the Relay struct with the message payload's value. The vehicles whose emergency state changed are stopped, or sent back the speed and lane they last requested.
     ///
-    /// Then it iterates over all incoming messages and checks if they are either Emergency ("GroupG/Emergency/I"), Zone ("GroupG/Zone/I") or Relay ("GroupG/Relay/") messages.
+    /// Relay messages must come from an allowed sender if senders were given (see with_senders), and be a command accepted by the relayed topic for a vehicle of the list, with values in range, otherwise they are dropped and the reason is published as a Rejection on the Rejected topic ("GroupG/Rejected/E").
     ///
-    /// Emergency and Zone messages are handled by updating the state of the Relay struct with the message payload's value.
+    /// Valid messages are evaluated by the policy, and are either relayed, rewritten or dropped. The speed and lane requested for each vehicle are remembered, and entering or leaving a slow zone sends the vehicle its own last requested speed through the policy.
     ///
-    /// Relay messages are handled by either relaying them as is, or by selectively overwriting them with a new speed.
-    fn loop_forever(mut self) {
-        let (mut client, connection) = Mqtt::new("group-g_relay");
-        client.subscribe(&Topic::Relay("#").get());
-        client.subscribe(&Topic::Emergency.get());
-        client.subscribe(&Topic::Zone.get());
-
-        for message in connection.start_loop() {
-            let payload_result = serde_json::from_slice(&message.payload);
-            let payload: serde_json::Value = match payload_result {
-                Ok(payload) => payload,
-                Err(e) => {
-                    dbg!(e);
-                    continue;
-                }
-            };
-
-            // Emergency messages handler
-            if message.topic == Topic::Emergency.get() {
-                self.emergency = match payload["payload"]["value"].as_bool() {
-                    Some(value) => value,
-                    None => {
-                        dbg!("match returned None");
-                        continue;
+    /// Returns an error only if the MQTT client can't be used anymore.
+    fn on_message(&mut self, ctx: &mut Context, message: &Publish) -> Result<(), Error> {
+        let ns = ctx.namespace;
+        match Topic::parse(&message.topic, ns) {
+            // Emergency messages handler, for the fleet, a single vehicle or a group
+            Some(
+                target @ (Topic::Emergency | Topic::VehicleEmergency(_) | Topic::GroupEmergency(_)),
+            ) => {
+                let value = match Payload::parse(&message.payload) {
+                    Ok(Payload::Emergency(value)) => value,
+                    other => {
+                        dbg!(&other);
+                        return Ok(());
                     }
                 };
 
-                let speed = if self.emergency { 0 } else { 200 };
+                let before: Vec<bool> = self
+                    .vehicle_list
+                    .iter()
+                    .map(|vehicle| self.emergency.is_stopped(vehicle))
+                    .collect();
+                self.emergency.update(target, value);
 
-                for vehicle in &self.vehicle_list {
-                    client.publish(
-                        &Topic::VehicleI(vehicle).get(),
-                        &Payload::Speed(speed, 1000).get(),
-                    );
+                for (vehicle, was_stopped) in self.vehicle_list.iter().zip(before) {
+                    match (was_stopped, self.emergency.is_stopped(vehicle)) {
+                        (false, true) => publish(
+                            ctx.client,
+                            &Topic::VehicleI(vehicle).get(ns),
+                            &Payload::Speed(0, 1000).get(),
+                        )?,
+                        (true, false) => self.restore(ctx, vehicle, true)?,
+                        _ => {}
+                    }
                 }
 
                 dbg!(&self.emergency);
+            }
 
             // Zone messages handler
-            } else if message.topic == Topic::Zone.get() {
+            Some(Topic::Zone) => {
                 // Fix for delayed behaviour in slow zones
                 let prev_inside_slow_zone = self.inside_slow_zone.clone();
 
-                self.inside_slow_zone = match payload["payload"]["value"].as_array() {
-                    Some(x) => x.iter().map(|x| x.as_str().unwrap().to_string()).collect(),
-                    None => continue,
+                self.inside_slow_zone = match Payload::parse(&message.payload) {
+                    // Vehicles outside the vehicle list can't be addressed, even through a zone
+                    Ok(Payload::Zone200(vehicles)) => vehicles
+                        .into_iter()
+                        .filter(|vehicle| self.vehicle_list.contains(vehicle))
+                        .collect(),
+                    other => {
+                        dbg!(&other);
+                        return Ok(());
+                    }
                 };
                 dbg!(&self.inside_slow_zone);
 
                 // Fix for delayed behaviour in slow zones
                 for vehicle in &prev_inside_slow_zone {
                     if !self.inside_slow_zone.contains(vehicle) {
-                        client.publish(
-                            &Topic::VehicleI(vehicle).get(),
-                            &Payload::Speed(self.last_speed as i16, 500).get(),
-                        );
+                        self.restore(ctx, vehicle, false)?;
                     }
                 }
 
                 for vehicle in &self.inside_slow_zone {
-                    client.publish(
-                        &Topic::VehicleI(vehicle).get(),
-                        &Payload::Speed(200, 1000).get(),
-                    );
+                    self.restore(ctx, vehicle, false)?;
                 }
+            }
 
-            // Any other message that will either get relayed or be overwritten
-            } else {
-                // Check if message.topic is correct (that is, it has a relayed topic in front)
-                // Or continue loop and handle next message
-                if !message.topic.contains(&Topic::Relay("").get()) {
-                    dbg!("message topic doesn't have relay prefix");
-                    continue;
+            // Any other message that will either get relayed, be overwritten or be rejected
+            Some(relay_topic @ Topic::Relay(topic)) => {
+                if let Some(senders) = &self.senders {
+                    let refused = match Payload::sender(&message.payload) {
+                        Some(sender) if senders.contains(&sender) => None,
+                        Some(sender) => Some(format!("unknown sender {}", sender)),
+                        None => Some(String::from("no sender")),
+                    };
+                    match refused {
+                        Some(reason) if self.ignore_unknown => {
+                            dbg!(reason, &message.topic);
+                            return Ok(());
+                        }
+                        Some(reason) => return reject(ctx.client, ns, message, reason),
+                        None => {}
+                    }
                 }
-                // Extract topic and vehicle ID from message.topic
-                let (_, topic) = message.topic.split_at(Topic::Relay("").get().len());
-                let vehicle_id = topic.split('/').collect::<Vec<&str>>()[3].to_string();
-
-                let payload_received =
-                    String::from_utf8(message.payload.to_vec()).expect("should be valid utf8");
-
-                let payload_sent = if payload["type"] == "speed" {
-                    self.last_speed = payload["payload"]["velocity"]
-                        .as_i64()
-                        .expect("should have a valid speed value");
-
-                    if self.emergency {
-                        Payload::Speed(0, 2000).get()
-                    } else if self.inside_slow_zone.contains(&vehicle_id) {
-                        Payload::Speed(200, 1000).get()
-                    } else {
-                        payload_received
+
+                let inner = relay_topic.relayed(ns);
+                let payload = match command(inner, &self.vehicle_list, &message.payload) {
+                    Ok(payload) => payload,
+                    Err(reason) => return reject(ctx.client, ns, message, reason),
+                };
+                // Vehicle ID of the relayed topic, if it is a vehicle topic
+                let vehicle_id = inner.and_then(|inner| inner.vehicle_id());
+
+                if let Some(id) = vehicle_id {
+                    let requested = self.requested.entry(id.to_string()).or_default();
+                    match payload {
+                        Payload::Speed(velocity, acceleration) => {
+                            requested.speed = Some((velocity, acceleration))
+                        }
+                        Payload::Lane(offset, velocity, acceleration) => {
+                            requested.lane = Some((offset, velocity, acceleration))
+                        }
+                        _ => {}
                     }
-                } else {
-                    payload_received
+                }
+
+                let (decision, rule) = self.policy.evaluate(&payload, &self.facts(vehicle_id));
+                self.report(ctx, topic, &decision, rule)?;
+                let payload = match decision {
+                    Decision::Pass => payload,
+                    Decision::Rewrite(payload) => payload,
+                    Decision::Drop => return Ok(()),
                 };
-                client.publish(topic, &payload_sent);
-                //dbg!(payload_sent);
+
+                if let (Payload::Lights(front, back), Some(id)) = (&payload, vehicle_id) {
+                    self.lights.insert(id.to_string(), (*front, *back));
+                }
+                // Sent as serialized by Payload, so no unknown field reaches the vehicle
+                publish(ctx.client, topic, &payload.get())?;
+            }
+
+            // Relayed topics unknown to Topic
+            None if message.topic.starts_with(&Topic::Relay("").get(ns)) => {
+                reject(ctx.client, ns, message, String::from(NOT_A_COMMAND_TOPIC))?;
+            }
+
+            _ => {
+                dbg!("unexpected topic", &message.topic);
             }
         }
+        Ok(())
     }
+}
 
-    /// Run the client and return it's thread handle.
-    pub fn run(self) -> thread::JoinHandle<()> {
-        thread::spawn(move || {
-            self.loop_forever();
-        })
+/// Parses a relayed message, checking it is a command accepted by the relayed topic and that its values are in range.
+///
+/// Vehicles of the list accept speed, lane, lights and connect commands, and the host accepts discover commands.
+fn command(topic: Option<Topic>, vehicles: &[String], message: &[u8]) -> Result<Payload, String> {
+    let for_vehicle = match topic {
+        Some(Topic::VehicleI(id)) if !vehicles.iter().any(|v| v == id) => {
+            return Err(format!(
+                "vehicle {} is not in the vehicle list of the relay",
+                id
+            ))
+        }
+        Some(Topic::VehicleI(_)) => true,
+        Some(Topic::HostI) => false,
+        _ => return Err(String::from(NOT_A_COMMAND_TOPIC)),
+    };
+    let payload = Payload::parse(message).map_err(|e| format!("not a valid command: {}", e))?;
+    let accepted = match payload {
+        Payload::Speed(..) | Payload::Lane(..) | Payload::Lights(..) | Payload::Connect(_) => {
+            for_vehicle
+        }
+        Payload::Discover(_) => !for_vehicle,
+        _ => false,
+    };
+    if !accepted {
+        return Err(format!("{:?} is not a command of this topic", payload));
     }
+    payload.validate()?;
+    Ok(payload)
+}
+
+/// Publishes why a relayed message was dropped on the Rejected topic.
+fn reject(
+    client: &mut ClientWrapper,
+    ns: &Namespace,
+    message: &Publish,
+    reason: String,
+) -> Result<(), Error> {
+    let rejection = Rejection {
+        topic: message.topic.clone(),
+        payload: String::from_utf8_lossy(&message.payload).into_owned(),
+        reason,
+    };
+    dbg!(&rejection);
+    publish(
+        client,
+        &Topic::Rejected.get(ns),
+        &serde_json::to_string(&rejection).expect("should be Ok(String)"),
+    )
 }
@@ -0,0 +1,423 @@
+//! In-process simulator of the hyperdrive host and its vehicles, to run the clients without a real track.
+//!
+//! The simulator connects to the broker like the hyperdrive host would. It answers discover messages on the host topic, and for every simulated vehicle it handles connect, speed, lane and lights messages on the vehicle topic.
+//!
+//! Vehicles drive around a TrackLayout, accelerating towards the requested speed and moving towards the requested lane offset. While connected they publish:
+//! * "E/track" events when entering a new track piece.
+//! * "E/wheelDistance" events every tick while moving, with the outer wheel travelling further in curves.
+//! * Retained status messages on "S" when starting, connecting or disconnecting, and periodically with the battery level.
+//!
+//! Negative velocities are treated as 0, reversing is not modelled.
+
+use crate::library::{
+    config::BrokerConfig,
+    controller::publish,
+    error::Error,
+    mqtt::{ClientWrapper, Mqtt},
+    payload::{Payload, TrackEvent, VehicleList, VehicleStatus, WheelDistanceEvent},
+    topic::{Namespace, Topic},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Direction of a track piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    Straight,
+    Left,
+    Right,
+}
+
+/// A single track piece with the ID reported in track events.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackPiece {
+    pub id: u64,
+    /// Length in millimetres.
+    pub length: f64,
+    pub curve: Curve,
+}
+
+/// A closed loop of track pieces driven in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackLayout {
+    pub pieces: Vec<TrackPiece>,
+}
+
+impl Default for TrackLayout {
+    /// An oval with the IDs of the pieces used on the lab track.
+    fn default() -> Self {
+        let piece = |id, length, curve| TrackPiece { id, length, curve };
+        TrackLayout {
+            pieces: vec![
+                piece(33, 340.0, Curve::Straight),
+                piece(39, 560.0, Curve::Straight),
+                piece(17, 280.0, Curve::Left),
+                piece(20, 280.0, Curve::Left),
+                piece(4, 560.0, Curve::Straight),
+                piece(21, 560.0, Curve::Straight),
+                piece(18, 280.0, Curve::Left),
+                piece(23, 280.0, Curve::Left),
+                piece(36, 220.0, Curve::Straight),
+            ],
+        }
+    }
+}
+
+impl TrackLayout {
+    /// Loads a layout from a TOML file with a list of pieces.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{Curve, TrackLayout};
+    ///
+    /// let layout = TrackLayout::from_toml(r#"
+    ///     [[pieces]]
+    ///     id = 33
+    ///     length = 560.0
+    ///     curve = "straight"
+    ///
+    ///     [[pieces]]
+    ///     id = 17
+    ///     length = 280.0
+    ///     curve = "left"
+    /// "#).unwrap();
+    /// assert_eq!(layout.pieces[1].curve, Curve::Left);
+    /// assert_eq!(layout.length(), 840.0);
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        TrackLayout::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Parses a layout from a TOML string, see from_file.
+    pub fn from_toml(content: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let layout: TrackLayout = toml::from_str(content)?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    /// Checks the layout has at least one piece and that every piece has a positive length.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pieces.is_empty()
+            || self
+                .pieces
+                .iter()
+                .any(|p| !p.length.is_finite() || p.length <= 0.0)
+        {
+            return Err(String::from(
+                "a layout needs at least one piece, all with a positive length",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Total length of the loop in millimetres.
+    pub fn length(&self) -> f64 {
+        self.pieces.iter().map(|p| p.length).sum()
+    }
+}
+
+/// How far apart the wheels of a vehicle are, relative to the curve radius. Makes the wheel distances differ by more than 4 in curves at normal speeds.
+const CURVE_WHEEL_RATIO: f64 = 0.1;
+/// Battery drain in percent per second while moving.
+const BATTERY_DRAIN: f64 = 0.05;
+/// How often the status of every connected vehicle is published.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State of a single simulated vehicle.
+struct SimVehicle {
+    id: String,
+    connected: bool,
+    battery: f64,
+    /// Index of the current piece in the layout.
+    piece: usize,
+    /// Distance driven on the current piece in millimetres.
+    position: f64,
+    speed: f64,
+    target_speed: f64,
+    acceleration: f64,
+    offset: f64,
+    target_offset: f64,
+    lane_speed: f64,
+    lights: (bool, bool),
+}
+
+impl SimVehicle {
+    fn new(id: &str, piece: usize) -> Self {
+        SimVehicle {
+            id: id.to_string(),
+            connected: false,
+            battery: 100.0,
+            piece,
+            position: 0.0,
+            speed: 0.0,
+            target_speed: 0.0,
+            acceleration: 0.0,
+            offset: 0.0,
+            target_offset: 0.0,
+            lane_speed: 0.0,
+            lights: (false, false),
+        }
+    }
+
+    fn status(&self) -> VehicleStatus {
+        VehicleStatus {
+            connected: self.connected,
+            battery: Some(self.battery.round() as u8),
+            model: Some(String::from("simulated")),
+        }
+    }
+
+    /// Handles a command sent to the vehicle, returns true if the status changed.
+    fn handle(&mut self, payload: Payload) -> bool {
+        match payload {
+            Payload::Connect(value) => {
+                let changed = self.connected != value;
+                self.connected = value;
+                if !value {
+                    self.target_speed = 0.0;
+                    self.speed = 0.0;
+                }
+                return changed;
+            }
+            // Disconnected vehicles ignore every other command
+            _ if !self.connected => {}
+            Payload::Speed(velocity, acceleration) => {
+                self.target_speed = f64::from(velocity.max(0));
+                self.acceleration = f64::from(acceleration);
+            }
+            Payload::Lane(offset, velocity, _) => {
+                self.target_offset = f64::from(offset);
+                self.lane_speed = f64::from(velocity);
+            }
+            Payload::Lights(front, back) => self.lights = (front, back),
+            _ => {}
+        }
+        false
+    }
+
+    /// Moves the vehicle by dt seconds and returns the events to publish.
+    fn step(&mut self, layout: &TrackLayout, dt: f64) -> Vec<(&'static str, String)> {
+        let mut events = Vec::new();
+
+        // Accelerate or brake towards the requested speed, an acceleration of 0 changes it at once
+        let max_change = if self.acceleration > 0.0 {
+            self.acceleration * dt
+        } else {
+            f64::INFINITY
+        };
+        self.speed += (self.target_speed - self.speed).clamp(-max_change, max_change);
+
+        let max_shift = self.lane_speed * dt;
+        self.offset += (self.target_offset - self.offset).clamp(-max_shift, max_shift);
+
+        if self.speed <= 0.0 {
+            return events;
+        }
+
+        let distance = self.speed * dt;
+        self.battery = (self.battery - BATTERY_DRAIN * dt).max(0.0);
+
+        let (left, right) = match layout.pieces[self.piece].curve {
+            Curve::Straight => (distance, distance),
+            Curve::Left => (
+                distance * (1.0 - CURVE_WHEEL_RATIO),
+                distance * (1.0 + CURVE_WHEEL_RATIO),
+            ),
+            Curve::Right => (
+                distance * (1.0 + CURVE_WHEEL_RATIO),
+                distance * (1.0 - CURVE_WHEEL_RATIO),
+            ),
+        };
+        events.push((
+            "wheelDistance",
+            serde_json::to_string(&WheelDistanceEvent {
+                left: left.round() as i64,
+                right: right.round() as i64,
+            })
+            .expect("should be Ok(String)"),
+        ));
+
+        self.position += distance;
+        while self.position >= layout.pieces[self.piece].length {
+            self.position -= layout.pieces[self.piece].length;
+            self.piece = (self.piece + 1) % layout.pieces.len();
+            events.push((
+                "track",
+                serde_json::to_string(&TrackEvent {
+                    track_id: layout.pieces[self.piece].id,
+                })
+                .expect("should be Ok(String)"),
+            ));
+        }
+
+        events
+    }
+}
+
+/// Simulates the hyperdrive host and a list of vehicles.
+pub struct Simulator {
+    vehicles: Vec<SimVehicle>,
+    layout: TrackLayout,
+    tick: Duration,
+}
+
+impl Simulator {
+    /// Creates a simulator with the given vehicle IDs, spread evenly over the layout.
+    ///
+    /// Returns an error if the layout isn't valid, see TrackLayout::validate.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{Simulator, TrackLayout};
+    ///
+    /// assert!(Simulator::new(&[String::from("d98ebab7c206")], TrackLayout::default()).is_ok());
+    /// assert!(Simulator::new(&[String::from("d98ebab7c206")], TrackLayout { pieces: Vec::new() }).is_err());
+    /// ```
+    pub fn new(vehicle_list: &[String], layout: TrackLayout) -> Result<Self, Error> {
+        layout.validate().map_err(Error::InvalidConfig)?;
+        let pieces = layout.pieces.len();
+        let vehicles = vehicle_list
+            .iter()
+            .enumerate()
+            .map(|(i, id)| SimVehicle::new(id, i * pieces / vehicle_list.len().max(1)))
+            .collect();
+
+        Ok(Simulator {
+            vehicles,
+            layout,
+            tick: Duration::from_millis(100),
+        })
+    }
+
+    /// Sets how often the vehicles are moved and their events published, 100ms by default.
+    ///
+    /// The tick can't be zero, run fails otherwise.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{BrokerConfig, Namespace, Simulator, TrackLayout};
+    /// use std::time::Duration;
+    ///
+    /// let simulator = Simulator::new(&[String::from("d98ebab7c206")], TrackLayout::default()).unwrap();
+    /// let handle = simulator.with_tick(Duration::ZERO).run(&BrokerConfig::default(), &Namespace::default());
+    /// assert!(handle.is_err());
+    /// ```
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    /// Runs the simulator in a separate thread, consuming it.
+    ///
+    /// Returns a handle to the thread, or an error if the tick is zero or the MQTT client couldn't be created.
+    pub fn run(
+        mut self,
+        config: &BrokerConfig,
+        namespace: &Namespace,
+    ) -> Result<thread::JoinHandle<()>, Error> {
+        if self.tick.is_zero() {
+            return Err(Error::InvalidConfig(String::from("tick is zero")));
+        }
+        let (mut client, connection) = Mqtt::new(&config.client_id("simulator"), config)?;
+        let rx = connection.start_loop();
+        let ns = namespace.clone();
+
+        Ok(thread::spawn(move || {
+            if let Err(e) = self.loop_forever(&mut client, rx, &ns) {
+                dbg!(e);
+            }
+        }))
+    }
+
+    fn loop_forever(
+        &mut self,
+        client: &mut ClientWrapper,
+        rx: std::sync::mpsc::Receiver<rumqttc::Publish>,
+        ns: &Namespace,
+    ) -> Result<(), Error> {
+        client.subscribe_with_retry(&Topic::HostI.get(ns), 5)?;
+        for vehicle in &self.vehicles {
+            client.subscribe_with_retry(&Topic::VehicleI(&vehicle.id).get(ns), 5)?;
+            publish_status(client, ns, vehicle)?;
+        }
+
+        let mut last_tick = Instant::now();
+        let mut last_status = Instant::now();
+
+        loop {
+            // Handle every command received since the last tick
+            while let Ok(message) = rx.try_recv() {
+                let payload = match Payload::parse(&message.payload) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        dbg!(e);
+                        continue;
+                    }
+                };
+
+                match Topic::parse(&message.topic, ns) {
+                    Some(Topic::HostI) if payload == Payload::Discover(true) => {
+                        let vehicles = VehicleList {
+                            value: self.vehicles.iter().map(|v| v.id.clone()).collect(),
+                        };
+                        publish(
+                            client,
+                            &Topic::HostS("vehicles").get(ns),
+                            &serde_json::to_string(&vehicles).expect("should be Ok(String)"),
+                        )?;
+                    }
+                    Some(Topic::VehicleI(id)) => {
+                        if let Some(vehicle) = self.vehicles.iter_mut().find(|v| v.id == id) {
+                            if vehicle.handle(payload) {
+                                publish_status(client, ns, vehicle)?;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            let dt = last_tick.elapsed().as_secs_f64();
+            last_tick = Instant::now();
+            let status_due = last_status.elapsed() >= STATUS_INTERVAL;
+            if status_due {
+                last_status = Instant::now();
+            }
+
+            for vehicle in self.vehicles.iter_mut().filter(|v| v.connected) {
+                for (event, payload) in vehicle.step(&self.layout, dt) {
+                    publish(
+                        client,
+                        &Topic::VehicleE(&vehicle.id, event).get(ns),
+                        &payload,
+                    )?;
+                }
+                if status_due {
+                    publish_status(client, ns, vehicle)?;
+                }
+            }
+
+            thread::sleep(self.tick);
+        }
+    }
+}
+
+/// Publishes the retained status of a vehicle, like the hyperdrive host does.
+fn publish_status(
+    client: &mut ClientWrapper,
+    ns: &Namespace,
+    vehicle: &SimVehicle,
+) -> Result<(), Error> {
+    let status = serde_json::to_string(&vehicle.status()).expect("should be Ok(String)");
+    match client.publish_retained_with_retry(&Topic::VehicleS(&vehicle.id).get(ns), &status, 5) {
+        Err(e) if e.is_fatal() => Err(e),
+        Err(e) => {
+            dbg!(e);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
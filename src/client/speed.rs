@@ -1,13 +1,36 @@
 //! This speed module is part of the steering controller.
 //!
 //! It contains the Speed struct and its implementation.
-use crate::library::{mqtt::Mqtt, payload::Payload, topic::Topic};
-use std::{thread, time::Duration};
+//!
+//! Every vehicle follows a SpeedProgram. Vehicles without a program of their own cycle through the velocity list, holding each velocity for the interval.
+use crate::library::{
+    config::BrokerConfig,
+    controller::{self, Context, Controller, RESOLUTION},
+    error::Error,
+    handle::ControllerHandle,
+    payload::Payload,
+    program::{Rng, SpeedProgram},
+    topic::{Namespace, Topic},
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 /// Struct holding lists of velocities and vehicles.
+#[derive(Clone)]
 pub struct Speed {
     velocity_list: Vec<i16>,
     vehicle_list: Vec<String>,
+    /// Time between two velocities, 3 seconds by default.
+    interval: Duration,
+    /// Acceleration sent with every velocity, 500 by default.
+    acceleration: u16,
+    /// Programs of the vehicles that don't follow the velocity list.
+    programs: HashMap<String, SpeedProgram>,
+    /// Where each vehicle is in its program, set on the first tick.
+    progress: HashMap<String, Progress>,
+    rng: Rng,
 }
 
 impl Speed {
@@ -16,29 +39,172 @@ impl Speed {
         Speed {
             velocity_list: velocity_list.to_owned(),
             vehicle_list: vehicle_list.to_owned(),
+            interval: Duration::from_secs(3),
+            acceleration: 500,
+            programs: HashMap::new(),
+            progress: HashMap::new(),
+            rng: Rng::new(),
         }
     }
+
+    /// Sets the time between two velocities.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the acceleration sent with every velocity.
+    pub fn with_acceleration(mut self, acceleration: u16) -> Self {
+        self.acceleration = acceleration;
+        self
+    }
+
+    /// Makes a vehicle follow its own program instead of the velocity list.
+    pub fn with_program(mut self, vehicle: &str, program: SpeedProgram) -> Self {
+        self.programs.insert(vehicle.to_string(), program);
+        self
+    }
     /// Main logic of the speed client.
     ///
-    /// Runs an infinite loop in a new thread, consuming the self and returning a handle to the thread.
+    /// Runs the client in a new thread until stopped, consuming the self and returning a handle to the thread.
+    ///
+    /// The client publishes the velocities of each vehicle's program in speed messages. Without programs, it publishes different velocities every interval (3 seconds by default) for each vehicle in vehicle_list.
     ///
-    /// The loop publishes different velocities in speed message every 3 seconds for each vehicle in vehicle_list.
-    pub fn run(self) -> thread::JoinHandle<()> {
-        let (mut client, connection) = Mqtt::new("groupg_speed");
-        let _rx = connection.start_loop();
-
-        thread::spawn(move || {
-            let mut i = 0;
-            loop {
-                for vehicle in &self.vehicle_list {
-                    client.publish(
-                        &Topic::Relay(&Topic::VehicleI(vehicle).get()).get(),
-                        &Payload::Speed(self.velocity_list[i], 500).get(),
-                    );
-                }
-                i = (i + 1) % self.velocity_list.len();
-                thread::sleep(Duration::from_secs(3));
+    /// Fails with Error::InvalidConfig if the velocity list is empty while a vehicle has no program, or if a program is invalid.
+    pub fn run(
+        self,
+        config: &BrokerConfig,
+        namespace: &Namespace,
+    ) -> Result<ControllerHandle, Error> {
+        controller::spawn(self, config, namespace)
+    }
+}
+
+impl Controller for Speed {
+    fn name(&self) -> &str {
+        "speed"
+    }
+
+    fn steering(&self) -> bool {
+        true
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let all_programmed = self
+            .vehicle_list
+            .iter()
+            .all(|vehicle| self.programs.contains_key(vehicle));
+        if !all_programmed {
+            if self.velocity_list.is_empty() {
+                return Err(Error::InvalidConfig(String::from("velocity list is empty")));
+            }
+            if self.interval.is_zero() {
+                return Err(Error::InvalidConfig(String::from("interval is zero")));
+            }
+        }
+        for (vehicle, program) in &self.programs {
+            if !self.vehicle_list.contains(vehicle) {
+                return Err(Error::InvalidConfig(format!(
+                    "program for unknown vehicle {}",
+                    vehicle
+                )));
+            }
+            program
+                .validate()
+                .map_err(|e| Error::InvalidConfig(format!("program of {}: {}", vehicle, e)))?;
+        }
+        Ok(())
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.interval.min(RESOLUTION))
+    }
+
+    /// Sends the velocities that are due.
+    fn on_tick(&mut self, ctx: &mut Context) -> Result<(), Error> {
+        let now = Instant::now();
+        for vehicle in &self.vehicle_list {
+            let progress = self.progress.entry(vehicle.clone()).or_insert_with(|| {
+                let program = self.programs.get(vehicle).cloned().unwrap_or_else(|| {
+                    SpeedProgram::cycle(&self.velocity_list, self.interval, self.acceleration)
+                });
+                Progress::new(program, now)
+            });
+            let Some((velocity, acceleration)) = progress.next(now, &mut self.rng) else {
+                continue;
+            };
+
+            // A full queue only skips this velocity, a stopped connection ends the client.
+            controller::publish(
+                ctx.client,
+                &Topic::Relay(&Topic::VehicleI(vehicle).get(ctx.namespace)).get(ctx.namespace),
+                &Payload::Speed(velocity, acceleration).get_from(ctx.client_id),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Position of a vehicle in its program.
+#[derive(Clone)]
+struct Progress {
+    program: SpeedProgram,
+    step: usize,
+    /// When the current step started.
+    started: Instant,
+    last_sent: Option<Instant>,
+    finished: bool,
+}
+
+impl Progress {
+    fn new(program: SpeedProgram, now: Instant) -> Self {
+        Progress {
+            program,
+            step: 0,
+            started: now,
+            last_sent: None,
+            finished: false,
+        }
+    }
+
+    /// Moves to the step of now, returning the velocity and acceleration to send if one is due.
+    fn next(&mut self, now: Instant, rng: &mut Rng) -> Option<(i16, u16)> {
+        if self.finished {
+            return None;
+        }
+
+        let mut step_changed = self.last_sent.is_none();
+        loop {
+            let step = &self.program.steps[self.step];
+            let elapsed = now.duration_since(self.started);
+            if elapsed < step.duration() {
+                break;
+            }
+            if self.step + 1 == self.program.steps.len() && !self.program.repeat {
+                // The last velocity is always sent, so the vehicle ends where it should even if the step was shorter than a tick
+                self.finished = true;
+                return Some((
+                    step.velocity_at(step.duration(), rng.next()),
+                    step.acceleration(),
+                ));
             }
-        })
+            self.started += step.duration();
+            self.step = (self.step + 1) % self.program.steps.len();
+            step_changed = true;
+        }
+
+        let step = &self.program.steps[self.step];
+        let update_due = step.is_continuous()
+            && self
+                .last_sent
+                .is_some_and(|sent| now.duration_since(sent) >= self.program.update_interval());
+        if !step_changed && !update_due {
+            return None;
+        }
+        self.last_sent = Some(now);
+        Some((
+            step.velocity_at(now.duration_since(self.started), rng.next()),
+            step.acceleration(),
+        ))
     }
 }
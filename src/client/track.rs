@@ -8,13 +8,25 @@
 //! This client subscribes to the event topic of each vehicle, receiving track ID and wheel distance messages.
 //! The client will only publish messages if there are any changes to the slow_vehicles list.
 
-use crate::library::{mqtt::Mqtt, payload::Payload, topic::Topic};
-use std::thread;
+use crate::library::{
+    config::BrokerConfig,
+    controller::{self, Context, Controller},
+    error::Error,
+    handle::ControllerHandle,
+    payload::{Payload, TrackEvent, WheelDistanceEvent},
+    topic::{Namespace, Topic},
+};
+use rumqttc::Publish;
 
+#[derive(Clone)]
 pub struct Track {
     vehicle_list: Vec<String>,
     slow_tracks: Vec<u64>,
     slow_vehicles: Vec<String>,
+    /// Last track ID received.
+    track_id: u64,
+    /// Whether the last wheel distances received differ by more than 4.
+    is_turning: bool,
 }
 
 impl Track {
@@ -23,112 +35,98 @@ impl Track {
             vehicle_list: vehicle_list.to_owned(),
             slow_tracks: slow_tracks.to_owned(),
             slow_vehicles: Vec::new(),
+            track_id: 0,
+            is_turning: false,
         }
     }
 
     /// Main logic of the track client.
     ///
-    /// Runs an infinite loop in a new thread, consuming the self and returning a handle to the thread.
+    /// Runs the client in a new thread until stopped, consuming the self and returning a handle to the thread.
     ///
-    /// The loop subscribes to the event topics "track" and "wheelDistance" of each vehicle in vehicle_list.
+    /// The client subscribes to the event topics "track" and "wheelDistance" of each vehicle in vehicle_list.
     ///
-    /// Whenever a new message is received, the data is extracted and saved to the corresponding variables.
+    /// Whenever a new message is received, the data is extracted and saved to the corresponding fields.
     ///
     /// To control whether a vehicle is turning, the difference between the left and right wheel distance is calculated. If the difference is greater than 4, the vehicle is turning.
     ///
     /// A list of slow vehicles is maintained. If a vehicle is on a slow track and is not in the list, it is added to the list. If a vehicle is not on a slow track and is in the list, it is removed from the list. This list is published on update to the zone topic.
-    pub fn run(mut self) -> thread::JoinHandle<()> {
-        let (mut client, connection) = Mqtt::new("groupg_track");
-
-        for vehicle in &self.vehicle_list {
-            client.subscribe(&Topic::VehicleE(vehicle, "track").get());
-            client.subscribe(&Topic::VehicleE(vehicle, "wheelDistance").get());
-        }
-
-        thread::spawn(move || {
-            let mut track_id: u64 = 0;
-            let mut prev_track_id: u64;
-            let mut is_turning: bool = false;
+    pub fn run(
+        self,
+        config: &BrokerConfig,
+        namespace: &Namespace,
+    ) -> Result<ControllerHandle, Error> {
+        controller::spawn(self, config, namespace)
+    }
+}
 
-            for message in connection.start_loop() {
-                let vehicle_id = message.topic.split('/').collect::<Vec<&str>>()[3].to_string();
-                let payload: serde_json::Value = match serde_json::from_slice(&message.payload) {
-                    Ok(payload) => payload,
-                    Err(e) => {
-                        dbg!("{}", e);
-                        continue;
-                    }
-                };
+impl Controller for Track {
+    fn name(&self) -> &str {
+        "track"
+    }
 
-                if message.topic.contains("track") {
-                    prev_track_id = track_id.clone();
-                    track_id = {
-                        match payload["trackId"].as_u64() {
-                            Some(track_id) => track_id,
-                            None => {
-                                dbg!("payload[\"trackId\"] returned None");
-                                continue;
-                            }
-                        }
-                    };
+    fn subscriptions(&self, ns: &Namespace) -> Vec<String> {
+        self.vehicle_list
+            .iter()
+            .flat_map(|vehicle| {
+                ["track", "wheelDistance"].map(|event| Topic::VehicleE(vehicle, event).get(ns))
+            })
+            .collect()
+    }
 
-                    // Print track_id and is_turning only if the track has changed.
-                    if track_id != prev_track_id {
-                        println!("track: {}, is_turning: {}", track_id, is_turning);
-                    }
+    fn on_message(&mut self, ctx: &mut Context, message: &Publish) -> Result<(), Error> {
+        let (vehicle_id, event) = match Topic::parse(&message.topic, ctx.namespace) {
+            Some(Topic::VehicleE(vehicle_id, event)) => (vehicle_id.to_string(), event),
+            _ => {
+                dbg!("unexpected topic", &message.topic);
+                return Ok(());
+            }
+        };
 
-                } else if message.topic.contains("wheelDistance") {
-                    let left = {
-                        match payload["left"].as_i64() {
-                            Some(left) => left,
-                            None => {
-                                dbg!("left returned None");
-                                continue;
-                            }
-                        }
-                    };
-                    let right = {
-                        match payload["right"].as_i64() {
-                            Some(right) => right,
-                            None => {
-                                dbg!("right returned None");
-                                continue;
-                            }
-                        }
-                    };
-                    is_turning = if (left - right).abs() > 4 {
-                        true
-                    } else {
-                        false
-                    };
+        if event == "track" {
+            let prev_track_id = self.track_id;
+            self.track_id = match serde_json::from_slice::<TrackEvent>(&message.payload) {
+                Ok(event) => event.track_id,
+                Err(e) => {
+                    dbg!(e);
+                    return Ok(());
                 }
+            };
 
-                // Update and publish slow_vehicles list only if necessary
-                if self.slow_tracks.contains(&track_id) && !self.slow_vehicles.contains(&vehicle_id)
-                {
-                    self.slow_vehicles.push(vehicle_id.clone());
+            // Print track_id and is_turning only if the track has changed.
+            if self.track_id != prev_track_id {
+                println!("track: {}, is_turning: {}", self.track_id, self.is_turning);
+            }
+        } else if event == "wheelDistance" {
+            let wheels = match serde_json::from_slice::<WheelDistanceEvent>(&message.payload) {
+                Ok(event) => event,
+                Err(e) => {
+                    dbg!(e);
+                    return Ok(());
+                }
+            };
+            self.is_turning = (wheels.left - wheels.right).abs() > 4;
+        }
 
-                    // Publish current list
-                    client.publish(
-                        &Topic::Zone.get(),
-                        &Payload::Zone200(&self.slow_vehicles).get(),
-                    );
-                } else if !self.slow_tracks.contains(&track_id)
-                    && self.slow_vehicles.contains(&vehicle_id)
-                {
-                    self.slow_vehicles.sort();
-                    self.slow_vehicles
-                        .binary_search(&vehicle_id)
-                        .ok()
-                        .map(|i| self.slow_vehicles.remove(i));
+        // Update and publish slow_vehicles list only if necessary
+        let on_slow_track = self.slow_tracks.contains(&self.track_id);
+        if on_slow_track && !self.slow_vehicles.contains(&vehicle_id) {
+            self.slow_vehicles.push(vehicle_id);
+        } else if !on_slow_track && self.slow_vehicles.contains(&vehicle_id) {
+            self.slow_vehicles.sort();
+            self.slow_vehicles
+                .binary_search(&vehicle_id)
+                .ok()
+                .map(|i| self.slow_vehicles.remove(i));
+        } else {
+            return Ok(());
+        }
 
-                    // Publish current list
-                    client.publish(
-                        &Topic::Zone.get(),
-                        &Payload::Zone200(&self.slow_vehicles).get(),
-                    );
-                }
-            }
-        })
+        // Publish current list, a dropped list being replaced by the next one
+        controller::publish(
+            ctx.client,
+            &Topic::Zone.get(ctx.namespace),
+            &Payload::Zone200(self.slow_vehicles.clone()).get(),
+        )
     }
 }
@@ -1,7 +1,7 @@
 //! Process Control Hyperdrive MQTT project (Rust version)
 //!
 //! This documentation can either be read from the source code or by using the "doc/index.html" (recommended). The latter was generated using "cargo doc" and can be found in the "doc" folder.
-//! 
+//!
 //! [Video demonstration link - Google Drive](https://drive.google.com/file/d/1ivpBfDTXD7pe8Fv8COzepab6ASXwm5Hu/view?usp=sharing)
 //!
 //! The project consists of 4 parts:
@@ -10,39 +10,84 @@
 //! * Track the tracks controller (track, relay)
 //! * Personal addition (track, relay)
 //!
+//! The binary is controlled from the command line, with subcommands to discover, connect and disconnect vehicles, toggle the emergency stop, and run any set of controllers (see "pc_mqtt_rs --help").
+//! A whole run can also be described in a Scenario file and started with "pc_mqtt_rs scenario <file>".
+//!
 //! All client implementations is found within the client module and shared code is found within the library module.
 //!
 //! # Available controllers/clients
-//! Each client module has a struct that holds some data about its purpose and a vehicle list. They all initialize a new MQTT client and run in their own thread.
+//! Each client module has a struct that holds some data about its purpose and a vehicle list. They all initialize a new MQTT client and run in their own thread, returning a ControllerHandle to stop them.
+//! On CTRL+C or SIGTERM the binary runs shutdown, which stops the steering controllers first, then brings the vehicles to speed 0, disconnects them and waits for every pending message to be sent.
+//! Every client implements the Controller trait and is run by the supervisor in the controller module, which owns its MQTT client, dispatches its messages and ticks, and restarts it if it panics. A Supervisor groups the controllers of a run, and custom controllers can be added to it the same way.
 //! Since all the communication is done through MQTT, they can be mixed and matched with their counterparts written in Python.
-//! The only limitation being that the relay client needs to be started before any other client, to avoid lost connect messages.
+//! Vehicles are connected with connect_vehicles, which waits for each vehicle to report it is connected, so the clients can be started in any order.
+//!
+//! Every client takes a BrokerConfig in its run function, which holds the broker address, transport (TCP, TLS, WS or WSS), credentials and other connection settings. It can be built in code, loaded from PC_MQTT_* environment variables or from a TOML file.
+//! Client IDs are made of a prefix, the client name and an instance suffix generated for each process (see BrokerConfig::client_id), and the binary refuses to start if another running instance announced one of its client IDs (see Presence).
+//! The clients also take a Namespace holding the topic prefixes ("GroupG", "hyperdrive" and "Anki" by default), so several groups can share one broker.
 //!
 //! ## Steering controllers
 //! ### Blink
 //! Every second it sends a message to each vehicle setting their lights to either on or off.
+//! It can also follow a LightPattern, controlling the front and back lights independently (on, off, blinking or strobing with their own period), alternating between vehicles, and switching to other patterns while a vehicle is inside a slow zone or during an emergency.
 //!
 //! ### Speed
 //! Every 3 seconds it sends a message to set the speed of each vehicle. The speed values are given to the controller with a list, and is iterated.
+//! Vehicles can also follow their own SpeedProgram instead, a sequence of steps with their own duration and acceleration that hold, ramp, oscillate or pick random velocities.
 //!
 //! ### Lane
 //! Every 5 seconds it sends a message to change lane of each vehicle by iterating a list of values given as an argument.
+//! Vehicles can also follow their own LaneSchedule instead, with lane changes timed per step, shifted per vehicle or triggered when entering a track piece, for example to stage overtakes.
 //!
 //! ## Emergency controller
 //! Both the emergency and personal addition controllers are implemented inside the relay module/client.
 //! The relay client is responsible for relaying messages from every other client to the broker. It will also handle emergency messages and personal addition (zone) messages, and if necessary overwrite any speed messages.
+//! Besides the emergency stop of the whole fleet, single vehicles and named groups of vehicles can be stopped and released on their own emergency topics, the speed messages being overwritten only for the stopped vehicles.
+//! The relay remembers the speed and lane last requested for each vehicle, so a vehicle leaving a slow zone or released from an emergency stop goes back to its own intended state.
+//! Relayed messages are validated first: anything that isn't a vehicle or host command, or has a velocity, acceleration or lane offset out of range, is dropped and the reason is published on the Rejected topic.
+//! Only vehicles of the relay's list can be addressed, and the relay can also require relayed commands to name their sender (see Payload::get_from), rejecting or ignoring those of unknown clients so stray test clients can't drive the vehicles.
+//! What happens to each relayed message is decided by a Policy, rules evaluated in order (emergency, speed limit zone, max fleet speed, lane lock, lights required or custom ones) that can pass, rewrite or drop it, configured in the relay settings of a scenario, and whose decisions can be reported on the Decisions topic.
 //!
 //! ## Tracking and personal addition controllers
 //! It receives and stores track ID numbers for each vehicle.
 //! If a vehicle is on a track that has a speed limit (velocity 200), it is appended to "slow_vehicles" list and published for the relay to take action.
+//!
+//! # Simulator
+//! The simulator client stands in for the hyperdrive host and its vehicles, so every controller can be run against a local broker without a track.
+//! It can be started with "cargo run --bin simulator -- <vehicle IDs>", the layout defaults to an oval and can be loaded from a TOML file given by PC_SIM_LAYOUT.
 
 mod client;
 mod library;
 
 pub use self::library::{
-    mqtt::{ClientWrapper, ConnectionWrapper, Mqtt},
-    payload::Payload,
-    topic::Topic,
-    util::{blocking_emergency_handler, connect_vehicles, discover_vehicles, set_ctrlc_handler},
+    config::{BrokerConfig, Credentials, TlsFiles, Transport},
+    controller::{Context, Controller, Supervisor},
+    error::Error,
+    handle::{ControllerHandle, StopToken},
+    mqtt::{ClientWrapper, ConnectionState, ConnectionWrapper, LastWill, Mqtt},
+    payload::{
+        Payload, Rejection, TrackEvent, VehicleList, VehicleStatus, WheelDistanceEvent,
+        MAX_ACCELERATION, MAX_OFFSET, MAX_VELOCITY,
+    },
+    policy::{Decision, Facts, Policy, PolicyConfig, PolicyReport, Report, Rule, RuleConfig},
+    presence::{Presence, PRESENCE_TIMEOUT},
+    program::{LaneSchedule, LaneStep, LightMode, LightPattern, SpeedProgram, SpeedStep},
+    scenario::{
+        BlinkConfig, Controllers, LaneConfig, RelayConfig, Scenario, SpeedConfig, TrackConfig,
+        VehicleConfig,
+    },
+    topic::{Namespace, OwnedTopic, Topic},
+    util::{
+        blocking_emergency_handler, connect_vehicles, disconnect_vehicles, discover_vehicles,
+        set_shutdown_handler, shutdown, ConnectedVehicle, Connecting, DiscoveredVehicle, Discovery,
+    },
 };
 
-pub use self::client::{blink::Blink, lane::Lane, relay::Relay, speed::Speed, track::Track};
+pub use self::client::{
+    blink::Blink,
+    lane::Lane,
+    relay::Relay,
+    simulator::{Curve, Simulator, TrackLayout, TrackPiece},
+    speed::Speed,
+    track::Track,
+};
@@ -0,0 +1,369 @@
+//! This module contains the broker connection settings shared by every client.
+//!
+//! A BrokerConfig can be built in code, loaded from environment variables or loaded from a TOML file, and is then passed to Mqtt::new and every client's run function.
+
+use super::error::Error;
+use rumqttc::Key;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    env, fmt, fs,
+    hash::{BuildHasher, Hasher},
+    path::{Path, PathBuf},
+    process,
+    sync::OnceLock,
+    time::Duration,
+};
+
+/// Username and password used to authenticate against the broker.
+///
+/// The password is redacted when debug printed, so logging a BrokerConfig doesn't leak it.
+/// # Example
+/// ```
+/// use pc_mqtt_rs::{BrokerConfig, Credentials};
+///
+/// let config = BrokerConfig {
+///     credentials: Some(Credentials { username: String::from("car"), password: String::from("hunter2") }),
+///     ..BrokerConfig::default()
+/// };
+/// let printed = format!("{:?}", config);
+/// assert!(printed.contains("car"));
+/// assert!(!printed.contains("hunter2"));
+/// ```
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Certificate files used by the TLS based transports. All files are PEM encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsFiles {
+    /// CA certificate used to verify the broker.
+    pub ca: PathBuf,
+    /// Optional client certificate, requires client_key.
+    pub client_cert: Option<PathBuf>,
+    /// Optional client key (PKCS#1 RSA or PKCS#8), requires client_cert.
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// Reads the certificate files into a rumqttc TLS configuration.
+    fn load(&self) -> Result<rumqttc::TlsConfiguration, Error> {
+        let ca = read_file(&self.ca)?;
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let cert = read_file(cert)?;
+                let key = read_file(key)?;
+                // rumqttc reads RSA keys as PKCS#1 and every other key as PKCS#8
+                let key = if String::from_utf8_lossy(&key).contains("BEGIN RSA PRIVATE KEY") {
+                    Key::RSA(key)
+                } else {
+                    Key::ECC(key)
+                };
+                Some((cert, key))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(Error::Transport(String::from(
+                    "client_cert and client_key must be set together",
+                )))
+            }
+        };
+
+        Ok(rumqttc::TlsConfiguration::Simple {
+            ca,
+            alpn: None,
+            client_auth,
+        })
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|e| Error::Transport(format!("{}: {}", path.display(), e)))
+}
+
+/// Transport used to reach the broker, plain TCP by default.
+///
+/// In a TOML file the transport is selected with its type key:
+/// ```
+/// use pc_mqtt_rs::{BrokerConfig, Transport};
+///
+/// let config = BrokerConfig::from_toml(r#"
+///     port = 8884
+///     [transport]
+///     type = "wss"
+///     path = "/mqtt"
+///     ca = "certs/ca.pem"
+/// "#).unwrap();
+/// assert!(matches!(config.transport, Transport::Wss { .. }));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Tls(TlsFiles),
+    /// WebSocket, the path is appended to the broker URL.
+    Ws {
+        path: String,
+    },
+    /// WebSocket over TLS.
+    Wss {
+        path: String,
+        #[serde(flatten)]
+        tls: TlsFiles,
+    },
+}
+
+impl Transport {
+    /// Converts the transport into its rumqttc counterpart, reading any certificate files.
+    pub(crate) fn load(&self) -> Result<rumqttc::Transport, Error> {
+        Ok(match self {
+            Transport::Tcp => rumqttc::Transport::Tcp,
+            Transport::Tls(tls) => rumqttc::Transport::Tls(tls.load()?),
+            Transport::Ws { .. } => rumqttc::Transport::Ws,
+            Transport::Wss { tls, .. } => rumqttc::Transport::Wss(tls.load()?),
+        })
+    }
+}
+
+/// Connection settings for the MQTT broker.
+///
+/// The default values point to the broker running on the Raspberry Pi.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrokerConfig {
+    /// Hostname or IP address of the broker.
+    pub host: String,
+    /// Port of the broker.
+    pub port: u16,
+    /// Transport used to reach the broker.
+    pub transport: Transport,
+    /// Optional credentials, none by default.
+    pub credentials: Option<Credentials>,
+    /// Keep-alive interval in seconds.
+    pub keep_alive: u64,
+    /// Whether the broker should discard the session on disconnect.
+    pub clean_session: bool,
+    /// Capacity of the request channel between a client and its connection.
+    pub capacity: usize,
+    /// First part of every client ID, "groupg" by default.
+    pub client_prefix: String,
+    /// Last part of every client ID, generated from the hostname, the process ID and a random number if not set.
+    pub instance: Option<String>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            host: String::from("192.168.4.1"),
+            port: 1883,
+            transport: Transport::Tcp,
+            credentials: None,
+            keep_alive: 60,
+            clean_session: true,
+            capacity: 10,
+            client_prefix: String::from("groupg"),
+            instance: None,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Creates a new config for the given broker, using default values for everything else.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::BrokerConfig;
+    ///
+    /// let config = BrokerConfig::new("localhost", 1883).with_credentials("user", "secret");
+    /// assert_eq!(config.host, "localhost");
+    /// assert_eq!(config.credentials.unwrap().username, "user");
+    /// ```
+    pub fn new(host: &str, port: u16) -> Self {
+        BrokerConfig {
+            host: host.to_string(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the credentials used to authenticate against the broker.
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some(Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        self
+    }
+
+    /// Sets the transport used to reach the broker.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Returns the broker address as expected by rumqttc, which is a URL for the WebSocket transports.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{BrokerConfig, Transport};
+    ///
+    /// let config = BrokerConfig::new("localhost", 9001).with_transport(Transport::Ws {
+    ///     path: String::from("/mqtt"),
+    /// });
+    /// assert_eq!(config.address(), "ws://localhost:9001/mqtt");
+    /// ```
+    pub fn address(&self) -> String {
+        match &self.transport {
+            Transport::Ws { path } => format!("ws://{}:{}{}", self.host, self.port, path),
+            Transport::Wss { path, .. } => format!("wss://{}:{}{}", self.host, self.port, path),
+            _ => self.host.clone(),
+        }
+    }
+
+    /// Returns the instance suffix of the client IDs, the generated one being the same for the whole process.
+    pub fn instance(&self) -> String {
+        self.instance
+            .clone()
+            .unwrap_or_else(|| generated_instance().to_string())
+    }
+
+    /// Returns the client ID of the named client, made of the prefix, the name and the instance suffix.
+    ///
+    /// Two processes sharing a broker must use different suffixes, otherwise the broker disconnects the first client when the second one connects.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::BrokerConfig;
+    ///
+    /// let mut config = BrokerConfig::default();
+    /// config.instance = Some(String::from("pi"));
+    /// assert_eq!(config.client_id("relay"), "groupg_relay_pi");
+    ///
+    /// config.instance = None;
+    /// assert_eq!(config.client_id("relay"), config.client_id("relay"));
+    /// assert_ne!(config.client_id("relay"), "groupg_relay_pi");
+    /// ```
+    pub fn client_id(&self, name: &str) -> String {
+        format!("{}_{}_{}", self.client_prefix, name, self.instance())
+    }
+
+    /// Returns the keep-alive interval as a Duration.
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive)
+    }
+
+    /// Loads the config from environment variables, falling back to the default values for unset variables.
+    ///
+    /// The recognised variables are PC_MQTT_HOST, PC_MQTT_PORT, PC_MQTT_USERNAME, PC_MQTT_PASSWORD, PC_MQTT_KEEP_ALIVE, PC_MQTT_CLEAN_SESSION, PC_MQTT_CAPACITY, PC_MQTT_CLIENT_PREFIX and PC_MQTT_INSTANCE.
+    ///
+    /// The transport is selected with PC_MQTT_TRANSPORT (tcp, tls, ws or wss), with PC_MQTT_CA, PC_MQTT_CLIENT_CERT, PC_MQTT_CLIENT_KEY and PC_MQTT_WS_PATH as its settings.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = BrokerConfig::default();
+
+        if let Ok(host) = env::var("PC_MQTT_HOST") {
+            config.host = host;
+        }
+        if let Ok(port) = env::var("PC_MQTT_PORT") {
+            config.port = port.parse()?;
+        }
+        if let Ok(username) = env::var("PC_MQTT_USERNAME") {
+            let password = env::var("PC_MQTT_PASSWORD").unwrap_or_default();
+            config = config.with_credentials(&username, &password);
+        }
+        if let Ok(keep_alive) = env::var("PC_MQTT_KEEP_ALIVE") {
+            config.keep_alive = keep_alive.parse()?;
+        }
+        if let Ok(clean_session) = env::var("PC_MQTT_CLEAN_SESSION") {
+            config.clean_session = clean_session.parse()?;
+        }
+        if let Ok(capacity) = env::var("PC_MQTT_CAPACITY") {
+            config.capacity = capacity.parse()?;
+        }
+        if let Ok(prefix) = env::var("PC_MQTT_CLIENT_PREFIX") {
+            config.client_prefix = prefix;
+        }
+        if let Ok(instance) = env::var("PC_MQTT_INSTANCE") {
+            config.instance = Some(instance);
+        }
+        if let Ok(transport) = env::var("PC_MQTT_TRANSPORT") {
+            let path = env::var("PC_MQTT_WS_PATH").unwrap_or_else(|_| String::from("/mqtt"));
+            let tls = || -> Result<TlsFiles, env::VarError> {
+                Ok(TlsFiles {
+                    ca: PathBuf::from(env::var("PC_MQTT_CA")?),
+                    client_cert: env::var("PC_MQTT_CLIENT_CERT").ok().map(PathBuf::from),
+                    client_key: env::var("PC_MQTT_CLIENT_KEY").ok().map(PathBuf::from),
+                })
+            };
+            config.transport = match transport.to_lowercase().as_str() {
+                "tcp" => Transport::Tcp,
+                "tls" => Transport::Tls(tls().map_err(|_| "PC_MQTT_CA must be set for tls")?),
+                "ws" => Transport::Ws { path },
+                "wss" => Transport::Wss {
+                    path,
+                    tls: tls().map_err(|_| "PC_MQTT_CA must be set for wss")?,
+                },
+                other => return Err(format!("unknown transport: {}", other).into()),
+            };
+        }
+
+        Ok(config)
+    }
+
+    /// Loads the config from a TOML file. Missing keys fall back to the default values.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        BrokerConfig::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the config from a TOML string, see from_file.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::BrokerConfig;
+    ///
+    /// let config = BrokerConfig::from_toml(r#"
+    ///     host = "147.87.116.34"
+    ///     keep_alive = 30
+    /// "#).unwrap();
+    /// assert_eq!(config.host, "147.87.116.34");
+    /// assert_eq!(config.port, 1883);
+    /// ```
+    pub fn from_toml(content: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+/// Instance suffix of this process, "<hostname>-<pid>-<random>".
+pub(crate) fn generated_instance() -> &'static str {
+    static INSTANCE: OnceLock<String> = OnceLock::new();
+    INSTANCE.get_or_init(|| {
+        let hostname = env::var("HOSTNAME")
+            .or_else(|_| fs::read_to_string("/etc/hostname"))
+            .unwrap_or_default();
+        let hostname: String = hostname
+            .trim()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .take(16)
+            .collect();
+        // RandomState is seeded randomly for every instance, which is enough to tell processes apart
+        let random = RandomState::new().build_hasher().finish() as u16;
+        format!(
+            "{}-{}-{:04x}",
+            if hostname.is_empty() {
+                "host"
+            } else {
+                &hostname
+            },
+            process::id(),
+            random
+        )
+    })
+}
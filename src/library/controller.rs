@@ -0,0 +1,339 @@
+//! This module contains the Controller trait implemented by every client, and the Supervisor running them.
+//!
+//! A controller only describes what to do with messages and ticks. The supervisor gives each controller its own MQTT client, subscribes to its topics, and calls it from a dedicated thread until it is stopped.
+//! If a callback panics the controller is replaced by a fresh copy of the one that was added, keeping the connection, up to MAX_RESTARTS times.
+//!
+//! # Example
+//! ```no_run
+//! use pc_mqtt_rs::{BrokerConfig, Context, Controller, Error, Namespace, Supervisor, Topic};
+//! use rumqttc::Publish;
+//!
+//! /// Prints every track event of a vehicle.
+//! #[derive(Clone)]
+//! struct Printer(String);
+//!
+//! impl Controller for Printer {
+//!     fn name(&self) -> &str {
+//!         "printer"
+//!     }
+//!
+//!     fn subscriptions(&self, ns: &Namespace) -> Vec<String> {
+//!         vec![Topic::VehicleE(&self.0, "track").get(ns)]
+//!     }
+//!
+//!     fn on_message(&mut self, _ctx: &mut Context, message: &Publish) -> Result<(), Error> {
+//!         println!("{:?}", message.payload);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut supervisor = Supervisor::new(&BrokerConfig::default(), &Namespace::default());
+//! supervisor.add(Printer(String::from("d98ebab7c206"))).unwrap();
+//! ```
+
+use super::{
+    config::BrokerConfig,
+    error::Error,
+    handle::{ControllerHandle, StopToken},
+    mqtt::{ClientWrapper, LastWill, Mqtt},
+    topic::Namespace,
+    util::shutdown,
+};
+use rumqttc::Publish;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+/// How many times a panicking controller is restarted before giving up.
+pub const MAX_RESTARTS: u32 = 5;
+/// Delay before a restart, multiplied by the number of restarts so far.
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+/// How often the stop token is checked while waiting for messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Longest time between two ticks of the controllers following programs, schedules or patterns.
+pub const RESOLUTION: Duration = Duration::from_millis(50);
+/// How many times a publish is retried while the request queue is full.
+const PUBLISH_RETRIES: u32 = 5;
+
+/// What a controller gets to act on the broker from its callbacks.
+pub struct Context<'a> {
+    pub client: &'a mut ClientWrapper,
+    pub namespace: &'a Namespace,
+    /// Client ID of the controller, sent as the sender of relayed commands (see Payload::get_from).
+    pub client_id: &'a str,
+}
+
+/// A client run by the Supervisor. Only name is required, every callback does nothing by default.
+///
+/// The name is also used in the client ID, see BrokerConfig::client_id.
+///
+/// Errors returned by the callbacks are logged, and stop the controller only if they are fatal.
+pub trait Controller: Send {
+    fn name(&self) -> &str;
+
+    /// Message the broker publishes if the controller dies without disconnecting.
+    fn last_will(&self, _ns: &Namespace) -> Option<LastWill> {
+        None
+    }
+
+    /// Steering controllers are stopped before the others on shutdown.
+    fn steering(&self) -> bool {
+        false
+    }
+
+    /// Checks the controller can run, called before it is started.
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Topic filters to subscribe to once started.
+    fn subscriptions(&self, _ns: &Namespace) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Called for every message received on a subscribed topic.
+    fn on_message(&mut self, _ctx: &mut Context, _message: &Publish) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Time between two calls of on_tick, the first one being right after the start. None disables ticks.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn on_tick(&mut self, _ctx: &mut Context) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called once when stopped, before the client disconnects.
+    fn shutdown(&mut self, _ctx: &mut Context) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Starts a controller in its own thread with its own MQTT client, returning a handle to stop it.
+///
+/// The controller is cloned before starting, the copy being used to restart it after a panic. Its topics are then subscribed to again, so it gets the retained messages (such as emergency stops) it would have got on a fresh start.
+pub fn spawn<C>(
+    controller: C,
+    config: &BrokerConfig,
+    namespace: &Namespace,
+) -> Result<ControllerHandle, Error>
+where
+    C: Controller + Clone + 'static,
+{
+    controller.validate()?;
+    let ns = namespace.clone();
+    let client_id = config.client_id(controller.name());
+    let (client, connection) = Mqtt::with_last_will(&client_id, config, controller.last_will(&ns))?;
+    let rx = connection.start_loop();
+    let name = controller.name().to_string();
+
+    Ok(ControllerHandle::spawn(
+        &name,
+        client,
+        move |stop, client| {
+            if let Err(e) = supervise(controller, stop, client, &rx, &ns, &client_id) {
+                log::error!("{} stopped: {}", client_id, e);
+            }
+        },
+    ))
+}
+
+/// Publishes a message, retrying while the queue is full, and returns whether it was queued.
+///
+/// A message still not queued after the retries is dropped and the error logged, only the errors meaning the client can't be used anymore are returned.
+pub fn try_publish(client: &mut ClientWrapper, topic: &str, payload: &str) -> Result<bool, Error> {
+    match client.publish_with_retry(topic, payload, PUBLISH_RETRIES) {
+        Ok(()) => Ok(true),
+        Err(e) if e.is_fatal() => Err(e),
+        Err(e) => {
+            log::warn!("message to {} dropped: {}", topic, e);
+            Ok(false)
+        }
+    }
+}
+
+/// Same as try_publish, when a dropped message doesn't need to be sent again.
+pub fn publish(client: &mut ClientWrapper, topic: &str, payload: &str) -> Result<(), Error> {
+    try_publish(client, topic, payload).map(|_| ())
+}
+
+/// Outcome of a single callback.
+enum Outcome {
+    Continue,
+    Stop(Error),
+    Panicked,
+}
+
+fn call<F>(name: &str, callback: F) -> Outcome
+where
+    F: FnOnce() -> Result<(), Error>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(callback)) {
+        Ok(Ok(())) => Outcome::Continue,
+        Ok(Err(e)) if e.is_fatal() => Outcome::Stop(e),
+        Ok(Err(e)) => {
+            log::warn!("{}: {}", name, e);
+            Outcome::Continue
+        }
+        Err(_) => Outcome::Panicked,
+    }
+}
+
+/// Subscribes to the topics of the controller.
+fn subscribe<C: Controller>(
+    controller: &C,
+    client: &mut ClientWrapper,
+    ns: &Namespace,
+) -> Result<(), Error> {
+    for topic in controller.subscriptions(ns) {
+        client.subscribe_with_retry(&topic, 5)?;
+    }
+    Ok(())
+}
+
+/// Dispatches messages and ticks to the controller until stopped.
+fn supervise<C>(
+    initial: C,
+    stop: &StopToken,
+    client: &mut ClientWrapper,
+    rx: &Receiver<Publish>,
+    ns: &Namespace,
+    client_id: &str,
+) -> Result<(), Error>
+where
+    C: Controller + Clone,
+{
+    subscribe(&initial, client, ns)?;
+
+    let name = initial.name().to_string();
+    let mut controller = initial.clone();
+    let mut restarts = 0;
+    let mut next_tick = Instant::now();
+
+    while !stop.is_stopped() {
+        let mut ctx = Context {
+            client: &mut *client,
+            namespace: ns,
+            client_id,
+        };
+        let tick_interval = controller.tick_interval();
+
+        let outcome = match tick_interval {
+            Some(interval) if Instant::now() >= next_tick => {
+                next_tick = Instant::now() + interval;
+                call(&name, || controller.on_tick(&mut ctx))
+            }
+            _ => {
+                let timeout = match tick_interval {
+                    Some(_) => next_tick.saturating_duration_since(Instant::now()),
+                    None => POLL_INTERVAL,
+                };
+                match rx.recv_timeout(timeout.min(POLL_INTERVAL)) {
+                    Ok(message) => call(&name, || controller.on_message(&mut ctx, &message)),
+                    Err(RecvTimeoutError::Timeout) => Outcome::Continue,
+                    Err(RecvTimeoutError::Disconnected) => Outcome::Stop(Error::Disconnected),
+                }
+            }
+        };
+
+        match outcome {
+            Outcome::Continue => {}
+            Outcome::Stop(e) => return Err(e),
+            Outcome::Panicked => {
+                restarts += 1;
+                if restarts > MAX_RESTARTS {
+                    log::error!("{} panicked {} times, giving up", name, restarts);
+                    return Ok(());
+                }
+                log::warn!(
+                    "{} panicked, restarting ({}/{})",
+                    name,
+                    restarts,
+                    MAX_RESTARTS
+                );
+                if stop.sleep(RESTART_DELAY * restarts) {
+                    break;
+                }
+                controller = initial.clone();
+                // Subscribing again makes the broker send the retained messages, such as an emergency stop, to the fresh copy
+                subscribe(&initial, client, ns)?;
+                next_tick = Instant::now();
+            }
+        }
+    }
+
+    let mut ctx = Context {
+        client,
+        namespace: ns,
+        client_id,
+    };
+    match call(&name, || controller.shutdown(&mut ctx)) {
+        Outcome::Stop(e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Runs a set of controllers and stops them together.
+pub struct Supervisor {
+    config: BrokerConfig,
+    namespace: Namespace,
+    steering: Vec<ControllerHandle>,
+    others: Vec<ControllerHandle>,
+}
+
+impl Supervisor {
+    pub fn new(config: &BrokerConfig, namespace: &Namespace) -> Self {
+        Supervisor {
+            config: config.clone(),
+            namespace: namespace.clone(),
+            steering: Vec::new(),
+            others: Vec::new(),
+        }
+    }
+
+    /// Starts a controller, see spawn.
+    pub fn add<C>(&mut self, controller: C) -> Result<(), Error>
+    where
+        C: Controller + Clone + 'static,
+    {
+        let steering = controller.steering();
+        let handle = spawn(controller, &self.config, &self.namespace)?;
+        if steering {
+            self.steering.push(handle);
+        } else {
+            self.others.push(handle);
+        }
+        Ok(())
+    }
+
+    /// Names of the controllers that are still running.
+    pub fn running(&self) -> Vec<&str> {
+        self.steering
+            .iter()
+            .chain(&self.others)
+            .filter(|handle| !handle.is_finished())
+            .map(|handle| handle.name())
+            .collect()
+    }
+
+    /// Stops every controller and the vehicles, see util::shutdown.
+    pub fn shutdown(
+        self,
+        client: &mut ClientWrapper,
+        vehicle_list: &[String],
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        shutdown(
+            client,
+            &self.namespace,
+            vehicle_list,
+            self.steering,
+            self.others,
+            timeout,
+        )
+    }
+}
@@ -0,0 +1,63 @@
+//! This module contains the emergency state followed by the relay and the blink controller.
+//!
+//! The whole fleet, single vehicles and named groups of vehicles can be stopped on their own emergency topics, a vehicle being stopped if any of them applies to it.
+
+use super::topic::{Namespace, Topic};
+use std::collections::BTreeMap;
+
+/// Emergency stops of the fleet, of single vehicles and of groups.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EmergencyState {
+    /// Emergency stop of the whole fleet.
+    pub fleet: bool,
+    /// Vehicles stopped on their own.
+    pub vehicles: Vec<String>,
+    /// Groups stopped, by name.
+    pub groups: Vec<String>,
+    /// Vehicles of each group that can be stopped together.
+    members: BTreeMap<String, Vec<String>>,
+}
+
+impl EmergencyState {
+    /// Names a group of vehicles, which can then be stopped and released together on its group emergency topic.
+    pub fn add_group(&mut self, name: &str, vehicles: &[String]) {
+        self.members.insert(name.to_string(), vehicles.to_owned());
+    }
+
+    /// The fleet, vehicle and group emergency topics.
+    pub fn topics(ns: &Namespace) -> Vec<String> {
+        vec![
+            Topic::Emergency.get(ns),
+            Topic::VehicleEmergency("+").get(ns),
+            Topic::GroupEmergency("+").get(ns),
+        ]
+    }
+
+    /// Applies the value of an emergency message, other topics being ignored.
+    pub fn update(&mut self, topic: Topic, value: bool) {
+        let (stopped, name) = match topic {
+            Topic::VehicleEmergency(name) => (&mut self.vehicles, name),
+            Topic::GroupEmergency(name) => (&mut self.groups, name),
+            Topic::Emergency => {
+                self.fleet = value;
+                return;
+            }
+            _ => return,
+        };
+        stopped.retain(|v| v != name);
+        if value {
+            stopped.push(name.to_string());
+        }
+    }
+
+    /// Returns true if the vehicle is stopped, by the fleet, its own or one of its groups emergency stop.
+    pub fn is_stopped(&self, vehicle: &str) -> bool {
+        self.fleet
+            || self.vehicles.iter().any(|v| v == vehicle)
+            || self
+                .groups
+                .iter()
+                .filter_map(|group| self.members.get(group))
+                .any(|members| members.iter().any(|v| v == vehicle))
+    }
+}
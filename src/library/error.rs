@@ -0,0 +1,58 @@
+//! This module contains the crate-level error type returned by the MQTT client wrapper.
+
+use std::fmt;
+
+/// Errors that can occur when sending requests through a ClientWrapper.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The request queue between the client and its connection is full. The request can be retried later.
+    QueueFull,
+    /// The connection event loop has stopped, no more requests can be sent.
+    Disconnected,
+    /// Another thread panicked while holding the client lock.
+    PoisonedLock,
+    /// The topic or topic filter is not valid MQTT.
+    InvalidTopic(String),
+    /// The transport couldn't be set up, for example because a certificate file is missing.
+    Transport(String),
+    /// A client was given settings it can't run with, for example an empty track layout or velocity list.
+    InvalidConfig(String),
+    /// A message payload couldn't be parsed.
+    InvalidPayload(String),
+}
+
+impl Error {
+    /// Returns true if the client can't be used anymore and the caller should shut down.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::Error;
+    ///
+    /// assert!(Error::Disconnected.is_fatal());
+    /// assert!(!Error::QueueFull.is_fatal());
+    /// ```
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Error::Disconnected | Error::PoisonedLock)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::QueueFull => write!(f, "request queue is full"),
+            Error::Disconnected => write!(f, "connection event loop has stopped"),
+            Error::PoisonedLock => write!(f, "client lock is poisoned"),
+            Error::InvalidTopic(topic) => write!(f, "invalid topic: {}", topic),
+            Error::Transport(reason) => write!(f, "transport setup failed: {}", reason),
+            Error::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            Error::InvalidPayload(reason) => write!(f, "invalid payload: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::InvalidPayload(e.to_string())
+    }
+}
@@ -0,0 +1,145 @@
+//! This module contains the handles used to stop the controllers.
+//!
+//! Every controller runs in its own thread, checking a StopToken between two messages. Stopping it through its ControllerHandle makes the thread return, after its MQTT client has sent every pending message and disconnected.
+
+use super::mqtt::ClientWrapper;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a waiting controller checks whether it was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a stopping controller waits for its pending messages to be sent.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Cancellation flag shared between a controller and whoever stops it.
+/// # Example
+/// ```
+/// use pc_mqtt_rs::StopToken;
+/// use std::time::Duration;
+///
+/// let token = StopToken::new();
+/// let clone = token.clone();
+/// assert!(!token.sleep(Duration::from_millis(10)));
+///
+/// clone.stop();
+/// assert!(token.is_stopped());
+/// assert!(token.sleep(Duration::from_secs(60)));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StopToken(Arc<AtomicBool>);
+
+impl StopToken {
+    pub fn new() -> Self {
+        StopToken::default()
+    }
+
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps for duration or until stopped, returning true if stopped.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.is_stopped() {
+                return true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            thread::sleep(remaining.min(POLL_INTERVAL));
+        }
+    }
+
+    /// Blocks until stopped.
+    pub fn wait(&self) {
+        while !self.sleep(Duration::from_secs(1)) {}
+    }
+
+    /// Waits for the next message, returning None once stopped or if the sender is gone.
+    pub fn recv<T>(&self, receiver: &Receiver<T>) -> Option<T> {
+        while !self.is_stopped() {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(message) => return Some(message),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        None
+    }
+}
+
+/// Handle to a running controller thread, returned by the run functions of the clients.
+pub struct ControllerHandle {
+    name: String,
+    stop: StopToken,
+    thread: thread::JoinHandle<()>,
+}
+
+impl ControllerHandle {
+    /// Runs body in a new thread with a fresh StopToken.
+    ///
+    /// Once body returns, the client sends its pending messages and disconnects, so the broker doesn't publish its Last Will.
+    pub(crate) fn spawn<F>(name: &str, mut client: ClientWrapper, body: F) -> Self
+    where
+        F: FnOnce(&StopToken, &mut ClientWrapper) + Send + 'static,
+    {
+        let stop = StopToken::new();
+        let token = stop.clone();
+        let thread = thread::spawn(move || {
+            body(&token, &mut client);
+            match client.close(CLOSE_TIMEOUT) {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::warn!("pending messages not sent before closing");
+                }
+                Err(e) => {
+                    log::warn!("closing the client failed: {}", e);
+                }
+            }
+        });
+
+        ControllerHandle {
+            name: name.to_string(),
+            stop,
+            thread,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Asks the controller to stop, without waiting for it.
+    pub fn stop(&self) {
+        self.stop.stop();
+    }
+
+    /// Returns true once the controller thread has returned, on its own or after being stopped.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for the controller thread to return, failing if it panicked.
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+
+    /// Stops the controller and waits for it.
+    pub fn stop_and_join(self) -> thread::Result<()> {
+        self.stop();
+        self.join()
+    }
+}
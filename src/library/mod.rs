@@ -1,4 +1,13 @@
+pub mod config;
+pub mod controller;
+pub mod emergency;
+pub mod error;
+pub mod handle;
 pub mod mqtt;
 pub mod payload;
+pub mod policy;
+pub mod presence;
+pub mod program;
+pub mod scenario;
 pub mod topic;
 pub mod util;
@@ -1,112 +1,403 @@
 //! This module contains the MQTT client and connection wrappers.
 //!
-//! To create a new client and connection pair use the "new" function.
+//! To create a new client and connection pair use the "new" function with a BrokerConfig.
 //! To maintain connection and receive incoming publish event notifications use the start_loop function.
+//!
+//! The connection loop reconnects with an exponential backoff when the broker goes away, and replays every subscription made through ClientWrapper::subscribe once it is back.
 
 #![allow(dead_code)]
 
-use rumqttc::{Client, Connection, Event, Incoming, MqttOptions, Publish, QoS};
+use super::{config::BrokerConfig, error::Error};
+use rumqttc::{
+    Client, ClientError, Connection, Event, Incoming, MqttOptions, Outgoing, Publish, QoS,
+};
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, Weak,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 pub struct Mqtt {}
 
+/// Message published by the broker on behalf of a client that disconnects without saying goodbye, for example because its process crashed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastWill {
+    pub topic: String,
+    pub payload: String,
+    /// Whether the broker should keep the message for clients subscribing later.
+    pub retain: bool,
+}
+
 impl Mqtt {
-    /// Creates a new MQTT Client/Connection pair.
+    /// Creates a new MQTT Client/Connection pair connected to the broker described by config.
+    ///
+    /// Fails if the configured transport can't be set up.
     /// # Example
-    /// ```
-    /// use pc_mqtt_rs::Mqtt;
+    /// ```no_run
+    /// use pc_mqtt_rs::{BrokerConfig, Mqtt};
     ///
-    /// let (mut client, connection) = Mqtt::new("doc_test");
+    /// let config = BrokerConfig::new("localhost", 1883);
+    /// let (mut client, connection) = Mqtt::new("doc_test", &config).unwrap();
     /// let rx = connection.start_loop();
     ///
-    /// client.subscribe("test/topic");
-    /// client.publish("test/topic", "test-payload");
+    /// client.subscribe("test/topic").unwrap();
+    /// client.publish("test/topic", "test-payload").unwrap();
     ///
     /// let received = rx.recv().unwrap().payload;
     /// assert_eq!(received, "test-payload");
     /// ```
-    pub fn new(client_id: &str) -> (ClientWrapper, ConnectionWrapper) {
-        let (client, connection) = Mqtt::init_client(client_id);
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        client_id: &str,
+        config: &BrokerConfig,
+    ) -> Result<(ClientWrapper, ConnectionWrapper), Error> {
+        Mqtt::with_last_will(client_id, config, None)
+    }
+
+    /// Same as new, but registers an optional Last Will message with the broker.
+    pub fn with_last_will(
+        client_id: &str,
+        config: &BrokerConfig,
+        last_will: Option<LastWill>,
+    ) -> Result<(ClientWrapper, ConnectionWrapper), Error> {
+        let (client, connection) = Mqtt::init_client(client_id, config, last_will)?;
         let client = Arc::new(Mutex::new(client));
-        (ClientWrapper { client }, ConnectionWrapper { connection })
+        let closed = Arc::new(AtomicBool::new(false));
+        let subscriptions = Arc::new(Mutex::new(Vec::new()));
+        Ok((
+            ClientWrapper {
+                client: client.clone(),
+                closed: closed.clone(),
+                subscriptions: subscriptions.clone(),
+            },
+            ConnectionWrapper {
+                connection,
+                client: Arc::downgrade(&client),
+                closed,
+                subscriptions,
+            },
+        ))
     }
 
-    fn set_options(client_id: &str) -> MqttOptions {
-        let mut options = MqttOptions::new(client_id, "192.168.4.1", 1883);
-        //let mut options = MqttOptions::new(client_id, "147.87.116.34", 1883); // For the non-PI broker
+    fn set_options(
+        client_id: &str,
+        config: &BrokerConfig,
+        last_will: Option<LastWill>,
+    ) -> Result<MqttOptions, Error> {
+        let mut options = MqttOptions::new(client_id, config.address(), config.port);
         options
-            //    .set_credentials("cedalo", "gCgZnxzl3liLFPCe5Vom2t5Ha") // For the non-PI broker
-            .set_transport(rumqttc::Transport::Tcp)
-            .set_keep_alive(Duration::from_secs(60));
+            .set_transport(config.transport.load()?)
+            .set_keep_alive(config.keep_alive())
+            .set_clean_session(config.clean_session);
 
-        options
+        if let Some(credentials) = &config.credentials {
+            options.set_credentials(credentials.username.as_str(), credentials.password.as_str());
+        }
+
+        if let Some(will) = last_will {
+            options.set_last_will(rumqttc::LastWill::new(
+                will.topic,
+                will.payload,
+                QoS::AtLeastOnce,
+                will.retain,
+            ));
+        }
+
+        Ok(options)
     }
 
-    fn init_client(client_id: &str) -> (Client, Connection) {
-        let (client, connection) = Client::new(Mqtt::set_options(client_id), 10);
-        (client, connection)
+    fn init_client(
+        client_id: &str,
+        config: &BrokerConfig,
+        last_will: Option<LastWill>,
+    ) -> Result<(Client, Connection), Error> {
+        let (client, connection) = Client::new(
+            Mqtt::set_options(client_id, config, last_will)?,
+            config.capacity,
+        );
+        Ok((client, connection))
     }
 }
 
 /// Rumqttc client wrapper, wraps the client in an Arc<Mutex<>> to allow sharing it between threads safely.
+///
+/// Requests are queued without blocking, so a full queue or a stopped connection is returned as an Error instead of blocking or panicking.
 pub struct ClientWrapper {
     client: Arc<Mutex<Client>>,
+    /// Set by the ConnectionWrapper once its event loop has stopped.
+    closed: Arc<AtomicBool>,
+    /// Topics subscribed to through this client, replayed by the ConnectionWrapper after a reconnect.
+    subscriptions: Arc<Mutex<Vec<String>>>,
 }
 
 impl ClientWrapper {
-    pub fn publish(&mut self, topic: &str, payload: &str) {
+    /// Queues a publish request with QoS 1.
+    pub fn publish(&mut self, topic: &str, payload: &str) -> Result<(), Error> {
+        self.publish_with_retain(topic, payload, false)
+    }
+
+    /// Queues a publish request with QoS 1 that the broker keeps as the last known value of the topic.
+    pub fn publish_retained(&mut self, topic: &str, payload: &str) -> Result<(), Error> {
+        self.publish_with_retain(topic, payload, true)
+    }
+
+    fn publish_with_retain(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        retain: bool,
+    ) -> Result<(), Error> {
+        if !rumqttc::valid_topic(topic) {
+            return Err(Error::InvalidTopic(topic.to_string()));
+        }
         self.client
             .lock()
-            .unwrap()
-            .publish(topic, QoS::AtLeastOnce, false, payload)
-            .unwrap();
+            .map_err(|_| Error::PoisonedLock)?
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+            .map_err(|e| self.map_error(e))
     }
 
-    pub fn subscribe(&mut self, topic: &str) {
+    /// Queues a publish request, retrying with a doubling backoff while the queue is full.
+    ///
+    /// Any other error is returned immediately.
+    pub fn publish_with_retry(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        retries: u32,
+    ) -> Result<(), Error> {
+        self.retry(retries, |client| client.publish(topic, payload))
+    }
+
+    /// Same as publish_with_retry, but the broker keeps the message as the last known value of the topic.
+    pub fn publish_retained_with_retry(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        retries: u32,
+    ) -> Result<(), Error> {
+        self.retry(retries, |client| client.publish_retained(topic, payload))
+    }
+
+    /// Queues a subscribe request with QoS 1. The topic is remembered and subscribed to again after a reconnect.
+    pub fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if !rumqttc::valid_filter(topic) {
+            return Err(Error::InvalidTopic(topic.to_string()));
+        }
         self.client
             .lock()
-            .unwrap()
-            .subscribe(topic, QoS::AtLeastOnce)
-            .unwrap();
+            .map_err(|_| Error::PoisonedLock)?
+            .try_subscribe(topic, QoS::AtLeastOnce)
+            .map_err(|e| self.map_error(e))?;
+
+        let mut subscriptions = self.subscriptions.lock().map_err(|_| Error::PoisonedLock)?;
+        if !subscriptions.iter().any(|t| t == topic) {
+            subscriptions.push(topic.to_string());
+        }
+        Ok(())
+    }
+
+    /// Queues a subscribe request, retrying with a doubling backoff while the queue is full.
+    pub fn subscribe_with_retry(&mut self, topic: &str, retries: u32) -> Result<(), Error> {
+        self.retry(retries, |client| client.subscribe(topic))
     }
 
-    pub fn unsubscribe(&mut self, topic: &str) {
-        self.client.lock().unwrap().unsubscribe(topic).unwrap();
+    /// Queues an unsubscribe request.
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.client
+            .lock()
+            .map_err(|_| Error::PoisonedLock)?
+            .try_unsubscribe(topic)
+            .map_err(|e| self.map_error(e))?;
+
+        self.subscriptions
+            .lock()
+            .map_err(|_| Error::PoisonedLock)?
+            .retain(|t| t != topic);
+        Ok(())
+    }
+
+    /// Queues a disconnect behind every pending request and waits up to timeout for the connection loop to send them and stop.
+    ///
+    /// Returns false if the loop didn't stop in time, for example because the broker can't be reached. The client can't be used anymore afterwards, nor can its clones.
+    pub fn close(&mut self, timeout: Duration) -> Result<bool, Error> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+        self.retry(5, |client| {
+            client
+                .client
+                .lock()
+                .map_err(|_| Error::PoisonedLock)?
+                .try_disconnect()
+                .map_err(|e| client.map_error(e))
+        })?;
+
+        let deadline = Instant::now() + timeout;
+        while !self.closed.load(Ordering::SeqCst) {
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(true)
     }
 
     pub fn arc_clone(&self) -> Self {
         ClientWrapper {
             client: self.client.clone(),
+            closed: self.closed.clone(),
+            subscriptions: self.subscriptions.clone(),
+        }
+    }
+
+    fn retry<F>(&mut self, retries: u32, mut request: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut Self) -> Result<(), Error>,
+    {
+        let mut backoff = Duration::from_millis(10);
+        let mut result = request(self);
+        for _ in 0..retries {
+            if result != Err(Error::QueueFull) {
+                break;
+            }
+            thread::sleep(backoff);
+            backoff *= 2;
+            result = request(self);
+        }
+        result
+    }
+
+    /// A blocking request only fails once the event loop has stopped. A non-blocking one reports a full queue and a stopped event loop as the same TryRequest error, so the closed flag is used to tell them apart.
+    ///
+    /// The flag is set in the Drop of ConnectionWrapper, before the connection and its request receiver are dropped, so a TryRequest failing because of a stopped loop always sees it.
+    fn map_error(&self, error: ClientError) -> Error {
+        match error {
+            ClientError::Request(_) => Error::Disconnected,
+            ClientError::TryRequest(_) if self.closed.load(Ordering::SeqCst) => Error::Disconnected,
+            ClientError::TryRequest(_) => Error::QueueFull,
         }
     }
 }
 
+/// Connection state changes reported by ConnectionWrapper::start_loop_with_state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The broker accepted the connection.
+    Connected,
+    /// The connection to the broker was lost.
+    Disconnected,
+    /// Waiting before the given reconnection attempt, starting at 1.
+    Reconnecting(u32),
+}
+
+/// Delay before the first reconnection attempt, doubled after every failed attempt.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+/// Upper bound of the reconnection delay.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
 pub struct ConnectionWrapper {
     connection: Connection,
+    /// Client of the connection, used to replay the subscriptions. Weak so dropping every ClientWrapper still stops the loop.
+    client: Weak<Mutex<Client>>,
+    closed: Arc<AtomicBool>,
+    subscriptions: Arc<Mutex<Vec<String>>>,
+}
+
+impl Drop for ConnectionWrapper {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
 }
 
 impl ConnectionWrapper {
     /// Iterates over Connection and send incoming publish event notifications over returned receiver.
-    pub fn start_loop(mut self) -> mpsc::Receiver<Publish> {
+    pub fn start_loop(self) -> mpsc::Receiver<Publish> {
+        let (rx, _state_rx) = self.start_loop_with_state();
+        rx
+    }
+
+    /// Same as start_loop, but also returns a receiver for connection state changes.
+    ///
+    /// The loop runs until the client is closed or every ClientWrapper of this connection is dropped. On connection errors it waits with an exponential backoff before reconnecting, and subscribes again to every topic if the broker didn't keep the session.
+    pub fn start_loop_with_state(
+        mut self,
+    ) -> (mpsc::Receiver<Publish>, mpsc::Receiver<ConnectionState>) {
         let (tx, rx) = mpsc::channel();
+        let (state_tx, state_rx) = mpsc::channel();
         thread::spawn(move || {
-            for notification in self.connection.iter() {
-                // send over only incoming publish event notifications
-                if let Ok(Event::Incoming(Incoming::Publish(notification))) = notification {
-                    match tx.send(notification) {
-                        Ok(_) => {}
-                        Err(e) => {
+            let mut connected = false;
+            let mut has_connected = false;
+            let mut attempt = 0;
+            let mut delay = MIN_RECONNECT_DELAY;
+
+            // recv only fails once all clients are dropped
+            while let Ok(notification) = self.connection.recv() {
+                match notification {
+                    Ok(Event::Incoming(Incoming::ConnAck(connack))) => {
+                        if has_connected && !connack.session_present {
+                            self.replay_subscriptions();
+                        }
+                        connected = true;
+                        has_connected = true;
+                        attempt = 0;
+                        delay = MIN_RECONNECT_DELAY;
+                        let _ = state_tx.send(ConnectionState::Connected);
+                    }
+                    // send over only incoming publish event notifications
+                    Ok(Event::Incoming(Incoming::Publish(notification))) => {
+                        if let Err(e) = tx.send(notification) {
                             dbg!("send attempt failed: {}", e);
-                            continue;
                         }
                     }
+                    // Every request queued before the disconnect has been sent, see ClientWrapper::close
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        let _ = state_tx.send(ConnectionState::Disconnected);
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        dbg!(e);
+                        if connected {
+                            connected = false;
+                            let _ = state_tx.send(ConnectionState::Disconnected);
+                        }
+                        attempt += 1;
+                        let _ = state_tx.send(ConnectionState::Reconnecting(attempt));
+                        thread::sleep(delay);
+                        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+        (rx, state_rx)
+    }
+
+    /// Subscribes again to every remembered topic through the client.
+    ///
+    /// The requests are sent from another thread, so a full request queue can't block the event loop that empties it. They are queued behind the requests already sent by the clients, so messages published on these topics in the meantime are missed, except for the retained ones.
+    fn replay_subscriptions(&self) {
+        let Some(client) = self.client.upgrade() else {
+            return;
+        };
+        let mut client = match client.lock() {
+            Ok(client) => client.clone(),
+            Err(_) => return,
+        };
+        let topics = match self.subscriptions.lock() {
+            Ok(topics) => topics.clone(),
+            Err(_) => return,
+        };
+        thread::spawn(move || {
+            for topic in topics {
+                // Only fails once the event loop is gone
+                if client.subscribe(topic, QoS::AtLeastOnce).is_err() {
+                    return;
                 }
             }
         });
-        rx
     }
 }
@@ -1,64 +1,272 @@
 //! This module contains payloads/messages used in the project, making it both easier to use and change them later on.
+//!
+//! Payload holds the commands sent to the vehicles and between the clients, while the event structs describe the messages published by the vehicles and the host.
 
 #![allow(dead_code)]
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+
+/// Highest velocity accepted in a speed or lane command, in both directions for speed.
+pub const MAX_VELOCITY: u16 = 1200;
+/// Highest acceleration accepted in a speed or lane command.
+pub const MAX_ACCELERATION: u16 = 2500;
+/// Highest lane offset accepted, in both directions.
+pub const MAX_OFFSET: i16 = 100;
 
 /// An enum that holds most of the payloads/messagess used in the project.
-pub enum Payload<'a> {
+///
+/// It is serialized as `{"type": ..., "payload": {...}}`, using the same field names as the Python clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Wire", into = "Wire")]
+pub enum Payload {
+    /// Velocity and acceleration.
     Speed(i16, u16),
     Connect(bool),
     Discover(bool),
+    /// Offset, velocity and acceleration.
     Lane(i16, u16, u16),
+    /// Front and back lights, true being on.
     Lights(bool, bool),
     Emergency(bool),
-    Zone200(&'a Vec<String>),
+    /// IDs of the vehicles inside a slow zone.
+    Zone200(Vec<String>),
 }
 
-impl Payload<'_> {
-    /// Formats hardcoded payload strings with the values inside the enum instances, if any.
+impl Payload {
+    /// Serializes the payload to its JSON string.
     /// # Example
     /// ```
     /// use pc_mqtt_rs::Payload;
     ///
-    /// assert_eq!(Payload::Speed(200, 1000).get(), String::from(r#"{"type":"speed","payload":{"velocity": 200,"acceleration": 1000}}"#));
+    /// assert_eq!(Payload::Speed(200, 1000).get(), String::from(r#"{"type":"speed","payload":{"velocity":200,"acceleration":1000}}"#));
     /// ```
     pub fn get(&self) -> String {
-        match self {
-            Payload::Speed(vel, acc) => {
-                format!(
-                    r#"{{"type":"speed","payload":{{"velocity": {},"acceleration": {}}}}}"#,
-                    vel, acc
-                )
-            }
-            Payload::Connect(value) => {
-                format!(r#"{{"type":"connect","payload":{{"value":{}}}}}"#, value)
-            }
-            Payload::Discover(value) => {
-                format!(r#"{{"type":"discover","payload":{{"value":{}}}}}"#, value)
-            }
-            Payload::Lane(offset, velocity, acceleration) => {
-                format!(
-                    r#"{{"type":"lane","payload":{{"offset":{},"velocity":{},"acceleration":{}}}}}"#,
-                    offset, velocity, acceleration
-                )
-            }
-            Payload::Lights(front, back) => {
-                format!(
-                    r#"{{"type":"lights","payload":{{"front":"{}","back":"{}"}}}}"#,
-                    if *front { "on" } else { "off" },
-                    if *back { "on" } else { "off" }
-                )
-            }
-            Payload::Emergency(value) => {
-                format!(r#"{{"type":"emergency","payload":{{"value":{}}}}}"#, value)
+        serde_json::to_string(self).expect("should be Ok(String)")
+    }
+
+    /// Serializes the payload with the client ID of its sender, which the relay can require (see Relay::with_senders).
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::Payload;
+    ///
+    /// let message = Payload::Speed(200, 1000).get_from("groupg_speed_pi");
+    /// assert_eq!(Payload::sender(message.as_bytes()), Some(String::from("groupg_speed_pi")));
+    /// assert_eq!(Payload::parse(message.as_bytes()).unwrap(), Payload::Speed(200, 1000));
+    /// assert_eq!(Payload::sender(Payload::Speed(200, 1000).get().as_bytes()), None);
+    /// ```
+    pub fn get_from(&self, sender: &str) -> String {
+        let mut message = serde_json::to_value(self).expect("should be Ok(Value)");
+        message["sender"] = sender.into();
+        message.to_string()
+    }
+
+    /// Returns the sender of a message serialized with get_from, if any.
+    pub fn sender(message: &[u8]) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Signed {
+            sender: String,
+        }
+        serde_json::from_slice::<Signed>(message)
+            .ok()
+            .map(|signed| signed.sender)
+    }
+
+    /// Parses a received message into a payload.
+    ///
+    /// Every payload parses back into itself:
+    /// ```
+    /// use pc_mqtt_rs::Payload;
+    ///
+    /// let payloads = [
+    ///     Payload::Speed(-300, 500),
+    ///     Payload::Connect(true),
+    ///     Payload::Discover(false),
+    ///     Payload::Lane(-20, 200, 500),
+    ///     Payload::Lights(true, false),
+    ///     Payload::Emergency(true),
+    ///     Payload::Zone200(vec![String::from("d98ebab7c206")]),
+    /// ];
+    /// for payload in payloads {
+    ///     assert_eq!(Payload::parse(payload.get().as_bytes()).unwrap(), payload);
+    /// }
+    /// ```
+    pub fn parse(message: &[u8]) -> Result<Payload, serde_json::Error> {
+        serde_json::from_slice(message)
+    }
+
+    /// Checks the values of a command are within what a vehicle accepts, returning a message naming the invalid value.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::Payload;
+    ///
+    /// assert!(Payload::Speed(-300, 500).validate().is_ok());
+    /// assert_eq!(
+    ///     Payload::Lane(250, 300, 500).validate(),
+    ///     Err(String::from("offset must be between -100 and 100")),
+    /// );
+    /// ```
+    pub fn validate(&self) -> Result<(), String> {
+        let (offset, velocity, acceleration) = match *self {
+            Payload::Speed(velocity, acceleration) => (0, velocity.unsigned_abs(), acceleration),
+            Payload::Lane(offset, velocity, acceleration) => (offset, velocity, acceleration),
+            _ => return Ok(()),
+        };
+        if !(-MAX_OFFSET..=MAX_OFFSET).contains(&offset) {
+            return Err(format!(
+                "offset must be between {} and {}",
+                -MAX_OFFSET, MAX_OFFSET
+            ));
+        }
+        if velocity > MAX_VELOCITY {
+            return Err(format!(
+                "velocity must be between -{} and {}",
+                MAX_VELOCITY, MAX_VELOCITY
+            ));
+        }
+        if acceleration > MAX_ACCELERATION {
+            return Err(format!(
+                "acceleration must not be greater than {}",
+                MAX_ACCELERATION
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Lights state as it is sent over the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LightState {
+    On,
+    Off,
+}
+
+impl From<bool> for LightState {
+    fn from(on: bool) -> Self {
+        if on {
+            LightState::On
+        } else {
+            LightState::Off
+        }
+    }
+}
+
+/// Wire format of Payload, tagged on "type" with the content in "payload".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "lowercase")]
+enum Wire {
+    Speed {
+        velocity: i16,
+        acceleration: u16,
+    },
+    Connect {
+        value: bool,
+    },
+    Discover {
+        value: bool,
+    },
+    Lane {
+        offset: i16,
+        velocity: u16,
+        acceleration: u16,
+    },
+    Lights {
+        front: LightState,
+        back: LightState,
+    },
+    Emergency {
+        value: bool,
+    },
+    Zone200 {
+        value: Vec<String>,
+    },
+}
+
+impl From<Wire> for Payload {
+    fn from(wire: Wire) -> Self {
+        match wire {
+            Wire::Speed {
+                velocity,
+                acceleration,
+            } => Payload::Speed(velocity, acceleration),
+            Wire::Connect { value } => Payload::Connect(value),
+            Wire::Discover { value } => Payload::Discover(value),
+            Wire::Lane {
+                offset,
+                velocity,
+                acceleration,
+            } => Payload::Lane(offset, velocity, acceleration),
+            Wire::Lights { front, back } => {
+                Payload::Lights(front == LightState::On, back == LightState::On)
             }
-            Payload::Zone200(value) => serde_json::to_string(&json!({
-                "type": "zone200",
-                "payload": {
-                    "value": value
-                }
-            }))
-            .expect("should be Ok(String)"),
+            Wire::Emergency { value } => Payload::Emergency(value),
+            Wire::Zone200 { value } => Payload::Zone200(value),
         }
     }
 }
+
+impl From<Payload> for Wire {
+    fn from(payload: Payload) -> Self {
+        match payload {
+            Payload::Speed(velocity, acceleration) => Wire::Speed {
+                velocity,
+                acceleration,
+            },
+            Payload::Connect(value) => Wire::Connect { value },
+            Payload::Discover(value) => Wire::Discover { value },
+            Payload::Lane(offset, velocity, acceleration) => Wire::Lane {
+                offset,
+                velocity,
+                acceleration,
+            },
+            Payload::Lights(front, back) => Wire::Lights {
+                front: front.into(),
+                back: back.into(),
+            },
+            Payload::Emergency(value) => Wire::Emergency { value },
+            Payload::Zone200(value) => Wire::Zone200 { value },
+        }
+    }
+}
+
+/// Published by a vehicle on its "track" event topic when it enters a new track piece.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackEvent {
+    pub track_id: u64,
+}
+
+/// Published by a vehicle on its "wheelDistance" event topic.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WheelDistanceEvent {
+    pub left: i64,
+    pub right: i64,
+}
+
+/// Published by the host on its "vehicles" status topic with the IDs of the discovered vehicles.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VehicleList {
+    pub value: Vec<String>,
+}
+
+/// Published by a vehicle on its status topic whenever its state changes.
+///
+/// Only the connected state is always present, the other fields depend on what the vehicle reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VehicleStatus {
+    pub connected: bool,
+    /// Battery level in percent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+/// Published by the relay on the Rejected topic when it drops an invalid relayed message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rejection {
+    /// Topic the message was received on.
+    pub topic: String,
+    /// Payload of the message, with invalid UTF-8 replaced.
+    pub payload: String,
+    pub reason: String,
+}
@@ -0,0 +1,385 @@
+//! This module contains the policy deciding what the Relay does with each relayed message.
+//!
+//! A Policy is a list of rules evaluated in order against every relayed message that parses into a Payload. Each rule gets the payload as rewritten by the rules before it, a rule dropping the message stops the evaluation, and the message is relayed as is if no rule changed it.
+//! The default policy holds the emergency and speed limit zone rules, which is what the relay always did. Other rules can be configured in the relay settings of a scenario, or implemented with the Rule trait.
+//!
+//! ```toml
+//! [controllers.relay.policy]
+//! report = "changes"
+//! rules = [
+//!     { rule = "emergency" },
+//!     { rule = "speed_limit_zone", limit = 200 },
+//!     { rule = "max_speed", max = 800 },
+//!     { rule = "lane_lock", vehicles = ["d98ebab7c206"] },
+//!     { rule = "lights_required" },
+//! ]
+//! ```
+//!
+//! Decisions can be reported on the Decisions topic ("GroupG/Decisions/E"), either only the rewrites and drops or every decision.
+
+use super::payload::Payload;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// What the relay knows about the recipient of a message when evaluating it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Facts<'a> {
+    /// ID of the vehicle the message is for, None if it isn't for a vehicle.
+    pub vehicle: Option<&'a str>,
+    /// Whether the vehicle is stopped by an emergency, or the fleet if the message isn't for a vehicle.
+    pub stopped: bool,
+    /// Whether the vehicle is inside a slow zone.
+    pub in_slow_zone: bool,
+    /// Last front and back lights relayed to the vehicle, if any.
+    pub lights: Option<(bool, bool)>,
+}
+
+/// What to do with a relayed message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Relay the message as it was received.
+    Pass,
+    /// Relay this payload instead.
+    Rewrite(Payload),
+    /// Don't relay the message.
+    Drop,
+}
+
+/// A rule of a Policy.
+pub trait Rule: Send + Sync {
+    /// Name used in the decision reports.
+    fn name(&self) -> &str;
+
+    fn evaluate(&self, payload: &Payload, facts: &Facts) -> Decision;
+}
+
+/// Stops the vehicles under an emergency stop, rewriting their speed messages to velocity 0.
+struct Emergency;
+
+impl Rule for Emergency {
+    fn name(&self) -> &str {
+        "emergency"
+    }
+
+    fn evaluate(&self, payload: &Payload, facts: &Facts) -> Decision {
+        match payload {
+            Payload::Speed(..) if facts.stopped => Decision::Rewrite(Payload::Speed(0, 2000)),
+            _ => Decision::Pass,
+        }
+    }
+}
+
+/// Lowers the velocity of the vehicles inside a slow zone to the limit.
+struct SpeedLimitZone {
+    limit: i16,
+}
+
+impl Rule for SpeedLimitZone {
+    fn name(&self) -> &str {
+        "speed_limit_zone"
+    }
+
+    fn evaluate(&self, payload: &Payload, facts: &Facts) -> Decision {
+        match *payload {
+            // A lower velocity, such as an emergency stop, is kept
+            Payload::Speed(velocity, _) if facts.in_slow_zone && velocity.abs() > self.limit => {
+                Decision::Rewrite(Payload::Speed(
+                    velocity.clamp(-self.limit, self.limit),
+                    1000,
+                ))
+            }
+            _ => Decision::Pass,
+        }
+    }
+}
+
+/// Clamps every velocity between -max and max.
+struct MaxSpeed {
+    max: i16,
+}
+
+impl Rule for MaxSpeed {
+    fn name(&self) -> &str {
+        "max_speed"
+    }
+
+    fn evaluate(&self, payload: &Payload, _facts: &Facts) -> Decision {
+        match *payload {
+            Payload::Speed(velocity, acceleration) if velocity.abs() > self.max => {
+                Decision::Rewrite(Payload::Speed(
+                    velocity.clamp(-self.max, self.max),
+                    acceleration,
+                ))
+            }
+            _ => Decision::Pass,
+        }
+    }
+}
+
+/// Returns true if the rule applies to the vehicle, an empty list meaning every vehicle.
+fn applies(vehicles: &[String], facts: &Facts) -> bool {
+    vehicles.is_empty()
+        || facts
+            .vehicle
+            .is_some_and(|id| vehicles.iter().any(|v| v == id))
+}
+
+/// Drops the lane changes of the listed vehicles.
+struct LaneLock {
+    vehicles: Vec<String>,
+}
+
+impl Rule for LaneLock {
+    fn name(&self) -> &str {
+        "lane_lock"
+    }
+
+    fn evaluate(&self, payload: &Payload, facts: &Facts) -> Decision {
+        match payload {
+            Payload::Lane(..) if applies(&self.vehicles, facts) => Decision::Drop,
+            _ => Decision::Pass,
+        }
+    }
+}
+
+/// Keeps the listed vehicles at velocity 0 until their front lights were turned on through the relay.
+struct LightsRequired {
+    vehicles: Vec<String>,
+}
+
+impl Rule for LightsRequired {
+    fn name(&self) -> &str {
+        "lights_required"
+    }
+
+    fn evaluate(&self, payload: &Payload, facts: &Facts) -> Decision {
+        let lights_on = facts.lights.is_some_and(|(front, _)| front);
+        match *payload {
+            Payload::Speed(velocity, acceleration)
+                if velocity != 0 && !lights_on && applies(&self.vehicles, facts) =>
+            {
+                Decision::Rewrite(Payload::Speed(0, acceleration))
+            }
+            _ => Decision::Pass,
+        }
+    }
+}
+
+fn default_limit() -> i16 {
+    200
+}
+
+/// A built-in rule and its parameters, as written in a scenario file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RuleConfig {
+    Emergency,
+    SpeedLimitZone {
+        /// Highest velocity inside a slow zone, 200 by default.
+        #[serde(default = "default_limit")]
+        limit: i16,
+    },
+    MaxSpeed {
+        max: i16,
+    },
+    LaneLock {
+        /// Vehicles that can't change lanes, every vehicle if empty.
+        #[serde(default)]
+        vehicles: Vec<String>,
+    },
+    LightsRequired {
+        /// Vehicles that need their lights on, every vehicle if empty.
+        #[serde(default)]
+        vehicles: Vec<String>,
+    },
+}
+
+impl RuleConfig {
+    fn build(&self) -> Arc<dyn Rule> {
+        match self {
+            RuleConfig::Emergency => Arc::new(Emergency),
+            RuleConfig::SpeedLimitZone { limit } => Arc::new(SpeedLimitZone { limit: *limit }),
+            RuleConfig::MaxSpeed { max } => Arc::new(MaxSpeed { max: *max }),
+            RuleConfig::LaneLock { vehicles } => Arc::new(LaneLock {
+                vehicles: vehicles.clone(),
+            }),
+            RuleConfig::LightsRequired { vehicles } => Arc::new(LightsRequired {
+                vehicles: vehicles.clone(),
+            }),
+        }
+    }
+}
+
+/// Which decisions are published on the Decisions topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Report {
+    #[default]
+    Off,
+    /// Only the rewrites and drops.
+    Changes,
+    All,
+}
+
+fn default_rules() -> Vec<RuleConfig> {
+    vec![
+        RuleConfig::Emergency,
+        RuleConfig::SpeedLimitZone {
+            limit: default_limit(),
+        },
+    ]
+}
+
+/// Rules and reporting of a Policy, as written in a scenario file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Rules in the order they are evaluated, the emergency and speed limit zone rules by default.
+    pub rules: Vec<RuleConfig>,
+    pub report: Report,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        PolicyConfig {
+            rules: default_rules(),
+            report: Report::Off,
+        }
+    }
+}
+
+impl PolicyConfig {
+    /// Checks the rules can be built, returning a message naming the invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            match rule {
+                RuleConfig::SpeedLimitZone { limit } if *limit < 0 => {
+                    return Err(format!("rules[{}].limit must not be negative", i))
+                }
+                RuleConfig::MaxSpeed { max } if *max < 0 => {
+                    return Err(format!("rules[{}].max must not be negative", i))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build(&self) -> Policy {
+        Policy {
+            rules: self.rules.iter().map(RuleConfig::build).collect(),
+            report: self.report,
+        }
+    }
+}
+
+/// Report of a decision, published as JSON on the Decisions topic.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyReport {
+    /// Topic the message was relayed to, or would have been.
+    pub topic: String,
+    /// "pass", "rewrite" or "drop".
+    pub decision: String,
+    /// Name of the rule that dropped the payload or last changed it, None if no rule did.
+    pub rule: Option<String>,
+    /// Payload relayed after a rewrite.
+    pub payload: Option<Payload>,
+}
+
+/// The rules the Relay evaluates against every relayed message.
+/// # Example
+/// ```
+/// use pc_mqtt_rs::{Decision, Facts, Payload, Policy};
+///
+/// let policy = Policy::default();
+/// let facts = Facts { vehicle: Some("d98ebab7c206"), stopped: false, in_slow_zone: true, lights: None };
+/// let (decision, rule) = policy.evaluate(&Payload::Speed(500, 500), &facts);
+/// assert_eq!(decision, Decision::Rewrite(Payload::Speed(200, 1000)));
+/// assert_eq!(rule, Some("speed_limit_zone"));
+///
+/// // The emergency stop is already below the limit of the zone
+/// let facts = Facts { stopped: true, ..facts };
+/// assert_eq!(
+///     policy.evaluate(&Payload::Speed(500, 500), &facts),
+///     (Decision::Rewrite(Payload::Speed(0, 2000)), Some("emergency")),
+/// );
+/// assert_eq!(policy.evaluate(&Payload::Speed(0, 2000), &facts), (Decision::Pass, None));
+/// ```
+#[derive(Clone)]
+pub struct Policy {
+    rules: Vec<Arc<dyn Rule>>,
+    report: Report,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        PolicyConfig::default().build()
+    }
+}
+
+impl Policy {
+    /// Creates a policy without rules, passing every message.
+    pub fn empty() -> Self {
+        Policy {
+            rules: Vec::new(),
+            report: Report::Off,
+        }
+    }
+
+    /// Adds a rule, evaluated after the ones already added.
+    pub fn with_rule<R: Rule + 'static>(mut self, rule: R) -> Self {
+        self.rules.push(Arc::new(rule));
+        self
+    }
+
+    /// Sets which decisions are reported.
+    pub fn with_report(mut self, report: Report) -> Self {
+        self.report = report;
+        self
+    }
+
+    /// Evaluates every rule in order, each on the payload as rewritten by the rules before it, and returns the decision with the name of the rule that made it.
+    ///
+    /// The first rule dropping the payload decides. Otherwise the payload is rewritten if it was changed, the last rule that changed it being named, and passed as is with no rule named if it wasn't.
+    pub fn evaluate(&self, payload: &Payload, facts: &Facts) -> (Decision, Option<&str>) {
+        let mut current = payload.clone();
+        let mut changed_by = None;
+        for rule in &self.rules {
+            match rule.evaluate(&current, facts) {
+                Decision::Pass => {}
+                Decision::Rewrite(rewritten) if rewritten == current => {}
+                Decision::Rewrite(rewritten) => {
+                    current = rewritten;
+                    changed_by = Some(rule.name());
+                }
+                Decision::Drop => return (Decision::Drop, Some(rule.name())),
+            }
+        }
+        if &current == payload {
+            (Decision::Pass, None)
+        } else {
+            (Decision::Rewrite(current), changed_by)
+        }
+    }
+
+    /// Returns the report of a decision if it should be published.
+    pub fn report(
+        &self,
+        topic: &str,
+        decision: &Decision,
+        rule: Option<&str>,
+    ) -> Option<PolicyReport> {
+        let (name, payload) = match (self.report, decision) {
+            (Report::Off, _) | (Report::Changes, Decision::Pass) => return None,
+            (_, Decision::Pass) => ("pass", None),
+            (_, Decision::Rewrite(payload)) => ("rewrite", Some(payload.clone())),
+            (_, Decision::Drop) => ("drop", None),
+        };
+        Some(PolicyReport {
+            topic: topic.to_string(),
+            decision: name.to_string(),
+            rule: rule.map(str::to_string),
+            payload,
+        })
+    }
+}
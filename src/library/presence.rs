@@ -0,0 +1,95 @@
+//! This module contains the presence messages used to detect client ID collisions.
+//!
+//! Every running instance publishes a retained Presence message listing its client IDs on "GroupG/Presence/<instance>", which is cleared when it exits, by itself or by its Last Will if it crashes.
+//! Before connecting its clients, an instance reads the retained messages and reports the client IDs already in use, since the broker would disconnect their owner.
+
+use super::{
+    config::{generated_instance, BrokerConfig},
+    error::Error,
+    mqtt::{ClientWrapper, LastWill, Mqtt},
+    topic::{Namespace, Topic},
+};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the retained presence messages.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Client IDs used by a running instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+    pub instance: String,
+    pub client_ids: Vec<String>,
+}
+
+impl Presence {
+    /// Creates the presence of the instance of config, with the client IDs of the named clients.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{BrokerConfig, Presence};
+    ///
+    /// let mut config = BrokerConfig::default();
+    /// config.instance = Some(String::from("pi"));
+    /// let presence = Presence::new(&config, &["main", "relay"]);
+    /// assert_eq!(presence.client_ids, vec!["groupg_main_pi", "groupg_relay_pi"]);
+    /// ```
+    pub fn new(config: &BrokerConfig, names: &[&str]) -> Self {
+        Presence {
+            instance: config.instance(),
+            client_ids: names.iter().map(|name| config.client_id(name)).collect(),
+        }
+    }
+
+    /// Last Will clearing the presence message if the client dies without withdrawing it.
+    pub fn last_will(&self, ns: &Namespace) -> LastWill {
+        LastWill {
+            topic: Topic::Presence(&self.instance).get(ns),
+            payload: String::new(),
+            retain: true,
+        }
+    }
+
+    /// Publishes the presence message, retained so instances started later see it.
+    pub fn announce(&self, client: &mut ClientWrapper, ns: &Namespace) -> Result<(), Error> {
+        let payload = serde_json::to_string(self).expect("should be Ok(String)");
+        client.publish_retained_with_retry(&Topic::Presence(&self.instance).get(ns), &payload, 5)
+    }
+
+    /// Clears the presence message, to be called before exiting.
+    pub fn withdraw(&self, client: &mut ClientWrapper, ns: &Namespace) -> Result<(), Error> {
+        client.publish_retained_with_retry(&Topic::Presence(&self.instance).get(ns), "", 5)
+    }
+
+    /// Returns the client IDs of this presence already announced by other instances.
+    ///
+    /// Connects with a client ID of its own, which can't collide with the ones being checked, and waits for the retained presence messages for timeout.
+    pub fn collisions(
+        &self,
+        config: &BrokerConfig,
+        ns: &Namespace,
+        timeout: Duration,
+    ) -> Result<Vec<String>, Error> {
+        let probe_id = format!("{}_probe_{}", config.client_prefix, generated_instance());
+        let (mut client, connection) = Mqtt::new(&probe_id, config)?;
+        let rx = connection.start_loop();
+        client.subscribe_with_retry(&Topic::Presence("+").get(ns), 5)?;
+
+        let mut in_use = Vec::new();
+        let deadline = Instant::now() + timeout;
+        while let Ok(message) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            // Empty payloads are withdrawn presences
+            let Ok(other) = serde_json::from_slice::<Presence>(&message.payload) else {
+                continue;
+            };
+            for id in other.client_ids {
+                if self.client_ids.contains(&id) && !in_use.contains(&id) {
+                    in_use.push(id);
+                }
+            }
+        }
+
+        client.close(Duration::from_secs(1))?;
+        Ok(in_use)
+    }
+}
@@ -0,0 +1,499 @@
+//! This module contains the programs and schedules followed by the steering controllers, describing what to send to a vehicle over time.
+//!
+//! A SpeedProgram is a list of steps, each lasting its own duration with its own acceleration. A step either holds a velocity, ramps linearly between two velocities, follows a sine wave or picks random velocities within bounds.
+//!
+//! ```toml
+//! [controllers.speed.programs.d98ebab7c206]
+//! update_ms = 500
+//! steps = [
+//!     { mode = "hold", velocity = 300, duration_ms = 3000 },
+//!     { mode = "ramp", from = 300, to = 800, duration_ms = 5000, acceleration = 300 },
+//!     { mode = "sine", center = 500, amplitude = 200, period_ms = 4000, duration_ms = 8000 },
+//!     { mode = "random", min = 300, max = 700, duration_ms = 6000 },
+//! ]
+//! ```
+//!
+//! A LaneSchedule is a list of lane changes, each sent after the previous one's duration or when the vehicle enters a given track piece, with a phase shift per vehicle.
+//!
+//! A LightPattern sets what the front and back lights do, each being on, off, blinking or strobing with its own period.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    f64::consts::PI,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+fn default_acceleration() -> u16 {
+    500
+}
+
+/// A step of a SpeedProgram.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SpeedStep {
+    /// Sends velocity once at the start of the step.
+    Hold {
+        velocity: i16,
+        duration_ms: u64,
+        #[serde(default = "default_acceleration")]
+        acceleration: u16,
+    },
+    /// Goes linearly from one velocity to the other over the step.
+    Ramp {
+        from: i16,
+        to: i16,
+        duration_ms: u64,
+        #[serde(default = "default_acceleration")]
+        acceleration: u16,
+    },
+    /// Oscillates around center, starting at center and going up first.
+    Sine {
+        center: i16,
+        amplitude: i16,
+        period_ms: u64,
+        duration_ms: u64,
+        #[serde(default = "default_acceleration")]
+        acceleration: u16,
+    },
+    /// Picks a new velocity between min and max, both included, on every update.
+    Random {
+        min: i16,
+        max: i16,
+        duration_ms: u64,
+        #[serde(default = "default_acceleration")]
+        acceleration: u16,
+    },
+}
+
+impl SpeedStep {
+    /// A step holding velocity for duration.
+    pub fn hold(velocity: i16, duration: Duration, acceleration: u16) -> Self {
+        SpeedStep::Hold {
+            velocity,
+            duration_ms: duration.as_millis() as u64,
+            acceleration,
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        match self {
+            SpeedStep::Hold { duration_ms, .. }
+            | SpeedStep::Ramp { duration_ms, .. }
+            | SpeedStep::Sine { duration_ms, .. }
+            | SpeedStep::Random { duration_ms, .. } => Duration::from_millis(*duration_ms),
+        }
+    }
+
+    pub fn acceleration(&self) -> u16 {
+        match self {
+            SpeedStep::Hold { acceleration, .. }
+            | SpeedStep::Ramp { acceleration, .. }
+            | SpeedStep::Sine { acceleration, .. }
+            | SpeedStep::Random { acceleration, .. } => *acceleration,
+        }
+    }
+
+    /// Returns true if the velocity changes during the step, so it is sent on every update.
+    pub fn is_continuous(&self) -> bool {
+        !matches!(self, SpeedStep::Hold { .. })
+    }
+
+    /// Returns the velocity to send once elapsed has passed since the start of the step.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::SpeedStep;
+    /// use std::time::Duration;
+    ///
+    /// let ramp = SpeedStep::Ramp { from: 300, to: 800, duration_ms: 5000, acceleration: 500 };
+    /// assert_eq!(ramp.velocity_at(Duration::ZERO, 0), 300);
+    /// assert_eq!(ramp.velocity_at(Duration::from_millis(2500), 0), 550);
+    ///
+    /// let sine = SpeedStep::Sine { center: 500, amplitude: 200, period_ms: 4000, duration_ms: 8000, acceleration: 500 };
+    /// assert_eq!(sine.velocity_at(Duration::from_millis(1000), 0), 700);
+    ///
+    /// let random = SpeedStep::Random { min: 300, max: 700, duration_ms: 6000, acceleration: 500 };
+    /// assert!((300..=700).contains(&random.velocity_at(Duration::ZERO, 12345)));
+    /// ```
+    pub fn velocity_at(&self, elapsed: Duration, random: u64) -> i16 {
+        let progress = (elapsed.as_secs_f64() / self.duration().as_secs_f64()).min(1.0);
+        match *self {
+            SpeedStep::Hold { velocity, .. } => velocity,
+            SpeedStep::Ramp { from, to, .. } => {
+                (from as f64 + (to as f64 - from as f64) * progress).round() as i16
+            }
+            SpeedStep::Sine {
+                center,
+                amplitude,
+                period_ms,
+                ..
+            } => {
+                let phase = 2.0 * PI * elapsed.as_millis() as f64 / period_ms as f64;
+                (center as f64 + amplitude as f64 * phase.sin()).round() as i16
+            }
+            SpeedStep::Random { min, max, .. } => {
+                let range = (max as i64 - min as i64 + 1) as u64;
+                (min as i64 + (random % range) as i64) as i16
+            }
+        }
+    }
+
+    /// Checks the step can be followed, returning a message naming the invalid field.
+    fn validate(&self) -> Result<(), String> {
+        if self.duration().is_zero() {
+            return Err(String::from("duration_ms must be greater than 0"));
+        }
+        match *self {
+            SpeedStep::Sine { period_ms: 0, .. } => {
+                Err(String::from("period_ms must be greater than 0"))
+            }
+            SpeedStep::Random { min, max, .. } if min > max => {
+                Err(String::from("min must not be greater than max"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn default_update_ms() -> u64 {
+    500
+}
+
+fn default_repeat() -> bool {
+    true
+}
+
+/// Velocities sent to one vehicle over time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeedProgram {
+    pub steps: Vec<SpeedStep>,
+    /// Time between two velocities of the continuous steps, 500 ms by default.
+    #[serde(default = "default_update_ms")]
+    pub update_ms: u64,
+    /// Whether to start over after the last step, true by default. Otherwise the last velocity is kept.
+    #[serde(default = "default_repeat")]
+    pub repeat: bool,
+}
+
+impl SpeedProgram {
+    /// Creates a repeating program from its steps.
+    pub fn new(steps: Vec<SpeedStep>) -> Self {
+        SpeedProgram {
+            steps,
+            update_ms: default_update_ms(),
+            repeat: true,
+        }
+    }
+
+    /// A program holding each velocity for interval in turn, which is what Speed does without programs.
+    pub fn cycle(velocities: &[i16], interval: Duration, acceleration: u16) -> Self {
+        SpeedProgram::new(
+            velocities
+                .iter()
+                .map(|velocity| SpeedStep::hold(*velocity, interval, acceleration))
+                .collect(),
+        )
+    }
+
+    pub fn update_interval(&self) -> Duration {
+        Duration::from_millis(self.update_ms)
+    }
+
+    /// Checks the program can be followed, returning a message naming the invalid field.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{SpeedProgram, SpeedStep};
+    ///
+    /// let program = SpeedProgram::new(vec![SpeedStep::Random { min: 700, max: 300, duration_ms: 1000, acceleration: 500 }]);
+    /// assert_eq!(program.validate().unwrap_err(), "steps[0].min must not be greater than max");
+    /// ```
+    pub fn validate(&self) -> Result<(), String> {
+        if self.steps.is_empty() {
+            return Err(String::from("steps must not be empty"));
+        }
+        if self.update_ms == 0 {
+            return Err(String::from("update_ms must be greater than 0"));
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            step.validate().map_err(|e| format!("steps[{}].{}", i, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn default_lane_velocity() -> u16 {
+    200
+}
+
+/// A lane change of a LaneSchedule.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LaneStep {
+    pub offset: i16,
+    /// Sideways velocity, 200 by default.
+    #[serde(default = "default_lane_velocity")]
+    pub velocity: u16,
+    /// Sideways acceleration, 500 by default.
+    #[serde(default = "default_acceleration")]
+    pub acceleration: u16,
+    /// Time between this lane change and the next step, 0 by default.
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// If set, the lane change waits for the vehicle to enter this track piece.
+    #[serde(default)]
+    pub on_track: Option<u64>,
+}
+
+impl LaneStep {
+    /// A lane change followed by a pause of duration.
+    pub fn timed(offset: i16, velocity: u16, acceleration: u16, duration: Duration) -> Self {
+        LaneStep {
+            offset,
+            velocity,
+            acceleration,
+            duration_ms: duration.as_millis() as u64,
+            on_track: None,
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+/// Lane changes of one vehicle, sent in order.
+///
+/// A step is sent once the previous one's duration has passed, or when the vehicle enters its track piece if it has one.
+/// ```toml
+/// [controllers.lane.schedules.d98ebab7c206]
+/// phase_ms = 2000
+/// steps = [
+///     { offset = -60, on_track = 20, duration_ms = 3000 },
+///     { offset = 60, velocity = 400, acceleration = 1000 },
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LaneSchedule {
+    pub steps: Vec<LaneStep>,
+    /// Delay before the first step, to shift vehicles against each other. 0 by default.
+    #[serde(default)]
+    pub phase_ms: u64,
+    /// Whether to start over after the last step, true by default.
+    #[serde(default = "default_repeat")]
+    pub repeat: bool,
+}
+
+impl LaneSchedule {
+    /// Creates a repeating schedule from its steps, without phase shift.
+    pub fn new(steps: Vec<LaneStep>) -> Self {
+        LaneSchedule {
+            steps,
+            phase_ms: 0,
+            repeat: true,
+        }
+    }
+
+    /// A schedule changing to each offset in turn every interval, which is what Lane does without schedules.
+    pub fn cycle(offsets: &[i16], interval: Duration, velocity: u16, acceleration: u16) -> Self {
+        LaneSchedule::new(
+            offsets
+                .iter()
+                .map(|offset| LaneStep::timed(*offset, velocity, acceleration, interval))
+                .collect(),
+        )
+    }
+
+    pub fn phase(&self) -> Duration {
+        Duration::from_millis(self.phase_ms)
+    }
+
+    /// Returns true if a step waits for a track piece, so the track events of the vehicle are needed.
+    pub fn uses_tracks(&self) -> bool {
+        self.steps.iter().any(|step| step.on_track.is_some())
+    }
+
+    /// Checks the schedule can be followed, returning a message naming the invalid field.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{LaneSchedule, LaneStep};
+    /// use std::time::Duration;
+    ///
+    /// let schedule = LaneSchedule::new(vec![LaneStep::timed(-60, 200, 500, Duration::ZERO)]);
+    /// assert_eq!(schedule.validate().unwrap_err(), "a repeating schedule needs a step with a duration_ms or an on_track");
+    /// ```
+    pub fn validate(&self) -> Result<(), String> {
+        if self.steps.is_empty() {
+            return Err(String::from("steps must not be empty"));
+        }
+        // Otherwise the schedule would send a lane change on every tick
+        if self.repeat
+            && self
+                .steps
+                .iter()
+                .all(|step| step.duration_ms == 0 && step.on_track.is_none())
+        {
+            return Err(String::from(
+                "a repeating schedule needs a step with a duration_ms or an on_track",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What a single light does over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase", deny_unknown_fields)]
+pub enum LightMode {
+    On,
+    Off,
+    /// On for the first half of every period, off for the second.
+    Blink {
+        period_ms: u64,
+        /// Shift of the period, for example half of it to blink out of step with the other light.
+        #[serde(default)]
+        phase_ms: u64,
+    },
+    /// On for on_ms at the start of every period.
+    Strobe {
+        period_ms: u64,
+        on_ms: u64,
+        #[serde(default)]
+        phase_ms: u64,
+    },
+}
+
+impl LightMode {
+    /// Returns whether the light is on once elapsed has passed, shifted by half a period if shifted is set.
+    pub fn is_on(&self, elapsed: Duration, shifted: bool) -> bool {
+        let position = |period_ms: u64, phase_ms: u64| {
+            let shift = if shifted { period_ms / 2 } else { 0 };
+            (elapsed.as_millis() as u64 + phase_ms + shift) % period_ms
+        };
+        match *self {
+            LightMode::On => true,
+            LightMode::Off => false,
+            LightMode::Blink {
+                period_ms,
+                phase_ms,
+            } => position(period_ms, phase_ms) < period_ms / 2,
+            LightMode::Strobe {
+                period_ms,
+                on_ms,
+                phase_ms,
+            } => position(period_ms, phase_ms) < on_ms,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            LightMode::Blink { period_ms, .. } if period_ms < 2 => {
+                Err(String::from("period_ms must be at least 2"))
+            }
+            LightMode::Strobe {
+                period_ms, on_ms, ..
+            } if on_ms == 0 || on_ms >= period_ms => Err(String::from(
+                "on_ms must be greater than 0 and less than period_ms",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Front and back light modes of a vehicle, sent with Payload::Lights.
+/// ```toml
+/// [controllers.blink.pattern]
+/// front = { mode = "blink", period_ms = 1000 }
+/// back = { mode = "strobe", period_ms = 1000, on_ms = 100, phase_ms = 500 }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightPattern {
+    pub front: LightMode,
+    pub back: LightMode,
+}
+
+impl LightPattern {
+    /// Both lights blinking together, on for half of period.
+    pub fn blink(period: Duration) -> Self {
+        let mode = LightMode::Blink {
+            period_ms: period.as_millis() as u64,
+            phase_ms: 0,
+        };
+        LightPattern {
+            front: mode,
+            back: mode,
+        }
+    }
+
+    /// Front and back lights blinking in turn.
+    pub fn alternate(period: Duration) -> Self {
+        let period_ms = period.as_millis() as u64;
+        LightPattern {
+            front: LightMode::Blink {
+                period_ms,
+                phase_ms: 0,
+            },
+            back: LightMode::Blink {
+                period_ms,
+                phase_ms: period_ms / 2,
+            },
+        }
+    }
+
+    /// Both lights flashing for on at the start of every period.
+    pub fn strobe(period: Duration, on: Duration) -> Self {
+        let mode = LightMode::Strobe {
+            period_ms: period.as_millis() as u64,
+            on_ms: on.as_millis() as u64,
+            phase_ms: 0,
+        };
+        LightPattern {
+            front: mode,
+            back: mode,
+        }
+    }
+
+    /// Returns the front and back lights once elapsed has passed, see LightMode::is_on.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::LightPattern;
+    /// use std::time::Duration;
+    ///
+    /// let pattern = LightPattern::alternate(Duration::from_millis(1000));
+    /// assert_eq!(pattern.lights(Duration::ZERO, false), (true, false));
+    /// assert_eq!(pattern.lights(Duration::from_millis(600), false), (false, true));
+    /// assert_eq!(pattern.lights(Duration::ZERO, true), (false, true));
+    /// ```
+    pub fn lights(&self, elapsed: Duration, shifted: bool) -> (bool, bool) {
+        (
+            self.front.is_on(elapsed, shifted),
+            self.back.is_on(elapsed, shifted),
+        )
+    }
+
+    /// Checks both modes, returning a message naming the invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        self.front.validate().map_err(|e| format!("front.{}", e))?;
+        self.back.validate().map_err(|e| format!("back.{}", e))
+    }
+}
+
+/// Small xorshift generator for the random steps, seeded differently in every process.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new() -> Self {
+        Rng(RandomState::new().build_hasher().finish() | 1)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -0,0 +1,414 @@
+//! This module contains the Scenario struct, describing a whole run in a single TOML or JSON file.
+//!
+//! A scenario holds the broker settings, the topic namespace, the vehicles and the controllers to start with their parameters. A controller is started only if its table is present.
+//!
+//! ```toml
+//! [broker]
+//! host = "192.168.4.1"
+//!
+//! [[vehicles]]
+//! id = "d98ebab7c206"
+//! name = "Skull"
+//!
+//! [controllers.relay]
+//! groups = { overtakers = ["d98ebab7c206"] }
+//! # Only relay the commands of this run's controllers and of the listed clients
+//! require_sender = true
+//! senders = ["python_speed"]
+//!
+//! # Optional, see the policy module
+//! [controllers.relay.policy]
+//! rules = [{ rule = "emergency" }, { rule = "speed_limit_zone" }, { rule = "max_speed", max = 800 }]
+//!
+//! [controllers.speed]
+//! velocities = [300, 400, 500]
+//! interval_ms = 3000
+//!
+//! # Optional, see the program module
+//! [controllers.speed.programs.d98ebab7c206]
+//! steps = [{ mode = "ramp", from = 300, to = 800, duration_ms = 5000 }]
+//!
+//! [controllers.lane]
+//! offsets = [0]
+//!
+//! # Optional, see the program module
+//! [controllers.lane.schedules.d98ebab7c206]
+//! steps = [{ offset = -60, on_track = 20, duration_ms = 3000 }, { offset = 60, on_track = 20 }]
+//!
+//! [controllers.track]
+//! slow_tracks = [20, 4, 21]
+//! ```
+
+use super::{
+    config::BrokerConfig,
+    policy::{PolicyConfig, RuleConfig},
+    program::{LaneSchedule, LightPattern, SpeedProgram},
+    topic::Namespace,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// A vehicle taking part in the scenario.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VehicleConfig {
+    pub id: String,
+    /// Friendly name, only used in the output.
+    pub name: Option<String>,
+}
+
+impl VehicleConfig {
+    /// Returns the friendly name, or the ID if there is none.
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// Parameters of the relay.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Vehicle IDs of the groups that can be stopped together, by group name.
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Rules applied to the relayed messages, the emergency and speed limit zone rules by default.
+    pub policy: PolicyConfig,
+    /// Whether relayed commands must name their sender, either a controller of this run or one of senders.
+    pub require_sender: bool,
+    /// Client IDs allowed to send commands besides the controllers of this run, such as the Python clients.
+    pub senders: Vec<String>,
+    /// Whether commands from unknown senders are dropped without being reported on the Rejected topic.
+    pub ignore_unknown: bool,
+}
+
+/// Parameters of the blink controller.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlinkConfig {
+    pub interval_ms: u64,
+    /// Pattern followed instead of toggling both lights every interval.
+    pub pattern: Option<LightPattern>,
+    /// Whether every other vehicle is shifted by half a period.
+    pub alternate: bool,
+    /// Pattern of the vehicles inside a slow zone.
+    pub zone: Option<LightPattern>,
+    /// Pattern of the vehicles stopped by an emergency, of the fleet, of the vehicle or of one of its relay groups.
+    pub emergency: Option<LightPattern>,
+}
+
+impl Default for BlinkConfig {
+    fn default() -> Self {
+        BlinkConfig {
+            interval_ms: 1000,
+            pattern: None,
+            alternate: false,
+            zone: None,
+            emergency: None,
+        }
+    }
+}
+
+/// Parameters of the speed controller.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeedConfig {
+    pub velocities: Vec<i16>,
+    pub acceleration: u16,
+    pub interval_ms: u64,
+    /// Programs of the vehicles that don't follow the velocities, by vehicle ID.
+    pub programs: BTreeMap<String, SpeedProgram>,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        SpeedConfig {
+            velocities: vec![500],
+            acceleration: 500,
+            interval_ms: 3000,
+            programs: BTreeMap::new(),
+        }
+    }
+}
+
+/// Parameters of the lane controller.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LaneConfig {
+    pub offsets: Vec<i16>,
+    pub velocity: u16,
+    pub acceleration: u16,
+    pub interval_ms: u64,
+    /// Schedules of the vehicles that don't follow the offsets, by vehicle ID.
+    pub schedules: BTreeMap<String, LaneSchedule>,
+}
+
+impl Default for LaneConfig {
+    fn default() -> Self {
+        LaneConfig {
+            offsets: vec![0],
+            velocity: 200,
+            acceleration: 500,
+            interval_ms: 5000,
+            schedules: BTreeMap::new(),
+        }
+    }
+}
+
+/// Parameters of the track controller.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackConfig {
+    pub slow_tracks: Vec<u64>,
+}
+
+/// The controllers to start, a missing one is not started.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Controllers {
+    pub relay: Option<RelayConfig>,
+    pub blink: Option<BlinkConfig>,
+    pub speed: Option<SpeedConfig>,
+    pub lane: Option<LaneConfig>,
+    pub track: Option<TrackConfig>,
+}
+
+impl Controllers {
+    /// Names of the controllers to start, which are also the names of their clients.
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            ("relay", self.relay.is_some()),
+            ("blink", self.blink.is_some()),
+            ("speed", self.speed.is_some()),
+            ("lane", self.lane.is_some()),
+            ("track", self.track.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
+    }
+}
+
+/// Everything needed for a run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    /// Broker settings, the environment variables are used if missing.
+    pub broker: Option<BrokerConfig>,
+    #[serde(default)]
+    pub namespace: Namespace,
+    pub vehicles: Vec<VehicleConfig>,
+    #[serde(default)]
+    pub controllers: Controllers,
+}
+
+impl Scenario {
+    /// Loads and validates a scenario, parsed as JSON if the file ends with ".json" and as TOML otherwise.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let scenario: Scenario = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&content)
+                .map_err(|e| format!("can't parse {}: {}", path.display(), e))?
+        } else {
+            toml::from_str(&content)
+                .map_err(|e| format!("can't parse {}: {}", path.display(), e))?
+        };
+        scenario.validate()
+    }
+
+    /// Parses and validates a scenario from a TOML string.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::Scenario;
+    ///
+    /// let scenario = Scenario::from_toml(r#"
+    ///     [[vehicles]]
+    ///     id = "d98ebab7c206"
+    ///     name = "Skull"
+    ///
+    ///     [controllers.speed]
+    ///     velocities = [300, 500]
+    /// "#).unwrap();
+    /// assert_eq!(scenario.vehicle_ids(), vec![String::from("d98ebab7c206")]);
+    /// assert_eq!(scenario.controllers.speed.unwrap().interval_ms, 3000);
+    /// assert!(scenario.controllers.relay.is_none());
+    ///
+    /// let error = Scenario::from_toml(r#"
+    ///     [[vehicles]]
+    ///     id = "d98ebab7c206"
+    ///
+    ///     [controllers.speed]
+    ///     velocities = []
+    /// "#).unwrap_err();
+    /// assert_eq!(error.to_string(), "controllers.speed.velocities must not be empty");
+    ///
+    /// // The velocities and offsets are only needed by vehicles without a program or schedule
+    /// let scenario = Scenario::from_toml(r#"
+    ///     [[vehicles]]
+    ///     id = "d98ebab7c206"
+    ///
+    ///     [controllers.speed]
+    ///     velocities = []
+    ///
+    ///     [controllers.speed.programs.d98ebab7c206]
+    ///     steps = [{ mode = "hold", velocity = 400, duration_ms = 1000 }]
+    ///
+    ///     [controllers.lane]
+    ///     offsets = []
+    ///
+    ///     [controllers.lane.schedules.d98ebab7c206]
+    ///     steps = [{ offset = 60, duration_ms = 1000 }]
+    /// "#);
+    /// assert!(scenario.is_ok());
+    /// ```
+    pub fn from_toml(content: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        toml::from_str::<Scenario>(content)?.validate()
+    }
+
+    /// Checks the scenario can be run, returning it unchanged or an error naming the invalid field.
+    pub fn validate(self) -> Result<Self, Box<dyn std::error::Error>> {
+        let scenario = self;
+        if scenario.vehicles.is_empty() {
+            return Err("vehicles must not be empty".into());
+        }
+        for (i, vehicle) in scenario.vehicles.iter().enumerate() {
+            if vehicle.id.is_empty() || vehicle.id.contains(['/', '+', '#']) {
+                return Err(format!("vehicles[{}].id is not a valid vehicle ID", i).into());
+            }
+            if scenario.vehicles[..i].iter().any(|v| v.id == vehicle.id) {
+                return Err(format!("vehicle {} is listed twice", vehicle.id).into());
+            }
+        }
+
+        let controllers = &scenario.controllers;
+        if let Some(relay) = &controllers.relay {
+            for (name, members) in &relay.groups {
+                if name.is_empty() || name.contains(['/', '+', '#']) {
+                    return Err(format!(
+                        "controllers.relay.groups.{} is not a valid group name",
+                        name
+                    )
+                    .into());
+                }
+                if members.is_empty() {
+                    return Err(
+                        format!("controllers.relay.groups.{} must not be empty", name).into(),
+                    );
+                }
+                if let Some(vehicle) = members
+                    .iter()
+                    .find(|id| !scenario.vehicles.iter().any(|v| &v.id == *id))
+                {
+                    return Err(format!(
+                        "controllers.relay.groups.{}: {} is not a vehicle of the scenario",
+                        name, vehicle
+                    )
+                    .into());
+                }
+            }
+            if !relay.require_sender && (!relay.senders.is_empty() || relay.ignore_unknown) {
+                return Err(
+                    "controllers.relay.senders and ignore_unknown need require_sender".into(),
+                );
+            }
+            if relay.senders.iter().any(|sender| sender.is_empty()) {
+                return Err("controllers.relay.senders must not contain an empty client ID".into());
+            }
+            relay
+                .policy
+                .validate()
+                .map_err(|e| format!("controllers.relay.policy.{}", e))?;
+            for (i, rule) in relay.policy.rules.iter().enumerate() {
+                let (RuleConfig::LaneLock { vehicles } | RuleConfig::LightsRequired { vehicles }) =
+                    rule
+                else {
+                    continue;
+                };
+                if let Some(vehicle) = vehicles
+                    .iter()
+                    .find(|id| !scenario.vehicles.iter().any(|v| &v.id == *id))
+                {
+                    return Err(format!(
+                        "controllers.relay.policy.rules[{}]: {} is not a vehicle of the scenario",
+                        i, vehicle
+                    )
+                    .into());
+                }
+            }
+        }
+        if let Some(blink) = &controllers.blink {
+            positive("controllers.blink.interval_ms", blink.interval_ms)?;
+            for (field, pattern) in [
+                ("pattern", &blink.pattern),
+                ("zone", &blink.zone),
+                ("emergency", &blink.emergency),
+            ] {
+                if let Some(pattern) = pattern {
+                    pattern
+                        .validate()
+                        .map_err(|e| format!("controllers.blink.{}.{}", field, e))?;
+                }
+            }
+        }
+        if let Some(speed) = &controllers.speed {
+            // Only the vehicles without a program follow the velocities
+            if scenario.some_without(&speed.programs) {
+                if speed.velocities.is_empty() {
+                    return Err("controllers.speed.velocities must not be empty".into());
+                }
+                positive("controllers.speed.interval_ms", speed.interval_ms)?;
+            }
+            scenario.per_vehicle("controllers.speed.programs", &speed.programs, |p| {
+                p.validate()
+            })?;
+        }
+        if let Some(lane) = &controllers.lane {
+            // Only the vehicles without a schedule follow the offsets
+            if scenario.some_without(&lane.schedules) {
+                if lane.offsets.is_empty() {
+                    return Err("controllers.lane.offsets must not be empty".into());
+                }
+                positive("controllers.lane.interval_ms", lane.interval_ms)?;
+            }
+            scenario.per_vehicle("controllers.lane.schedules", &lane.schedules, |s| {
+                s.validate()
+            })?;
+        }
+        Ok(scenario)
+    }
+
+    /// Returns true if some vehicle has no entry in a map keyed by vehicle ID.
+    fn some_without<T>(&self, map: &BTreeMap<String, T>) -> bool {
+        self.vehicles.iter().any(|v| !map.contains_key(&v.id))
+    }
+
+    /// Checks every entry of a map keyed by vehicle ID is a vehicle of the scenario and is valid.
+    fn per_vehicle<T>(
+        &self,
+        field: &str,
+        map: &BTreeMap<String, T>,
+        validate: impl Fn(&T) -> Result<(), String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (vehicle, value) in map {
+            if !self.vehicles.iter().any(|v| &v.id == vehicle) {
+                return Err(
+                    format!("{}.{} is not a vehicle of the scenario", field, vehicle).into(),
+                );
+            }
+            validate(value).map_err(|e| format!("{}.{}.{}", field, vehicle, e))?;
+        }
+        Ok(())
+    }
+
+    /// IDs of the vehicles in the order they are listed.
+    pub fn vehicle_ids(&self) -> Vec<String> {
+        self.vehicles.iter().map(|v| v.id.clone()).collect()
+    }
+}
+
+fn positive(field: &str, value: u64) -> Result<(), Box<dyn std::error::Error>> {
+    if value == 0 {
+        return Err(format!("{} must be greater than 0", field).into());
+    }
+    Ok(())
+}
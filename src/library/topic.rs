@@ -1,8 +1,56 @@
 //! This module contains the topics, making it both easier to use and change them later on.
+//!
+//! The prefixes of the topics are held by a Namespace, so several groups can share one broker without colliding.
 
 #![allow(dead_code)]
 
+use serde::{Deserialize, Serialize};
+use std::env;
+
+/// Prefixes used to build the topic strings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Namespace {
+    /// Prefix of the topics owned by this project (relay, emergency and zone), "GroupG" by default.
+    pub group: String,
+    /// ID of the hyperdrive host, "hyperdrive" by default.
+    pub host: String,
+    /// Root of the vehicle and host topics, "Anki" by default.
+    pub root: String,
+}
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Namespace {
+            group: String::from("GroupG"),
+            host: String::from("hyperdrive"),
+            root: String::from("Anki"),
+        }
+    }
+}
+
+impl Namespace {
+    /// Creates a namespace with the given group prefix, using the default host and root.
+    pub fn new(group: &str) -> Self {
+        Namespace {
+            group: group.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Loads the namespace from the PC_MQTT_GROUP, PC_MQTT_HOST_ID and PC_MQTT_ROOT environment variables, falling back to the default values for unset variables.
+    pub fn from_env() -> Self {
+        let default = Namespace::default();
+        Namespace {
+            group: env::var("PC_MQTT_GROUP").unwrap_or(default.group),
+            host: env::var("PC_MQTT_HOST_ID").unwrap_or(default.host),
+            root: env::var("PC_MQTT_ROOT").unwrap_or(default.root),
+        }
+    }
+}
+
 /// An enum that holds almost all the topics used in the project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Topic<'a> {
     HostI,
     HostS(&'a str),
@@ -12,28 +60,215 @@ pub enum Topic<'a> {
     Relay(&'a str),
     SpeedE(&'a str),
     Emergency,
+    /// Emergency stop of a single vehicle.
+    VehicleEmergency(&'a str),
+    /// Emergency stop of a named group of vehicles, see Relay::with_group.
+    GroupEmergency(&'a str),
     Zone,
+    /// Presence of a running instance, see the presence module.
+    Presence(&'a str),
+    /// Reports of the relay policy decisions, see the policy module.
+    Decisions,
+    /// Relayed messages dropped by the relay because they are invalid, see Rejection.
+    Rejected,
 }
 
-impl Topic<'_> {
-    /// Formats hardcoded topic strings with the values inside the enum instances, if any.
+impl<'a> Topic<'a> {
+    /// Formats topic strings inside the given namespace with the values inside the enum instances, if any.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{Namespace, Topic};
+    ///
+    /// let ns = Namespace::default();
+    /// assert_eq!(Topic::VehicleI("test").get(&ns), String::from("Anki/Vehicles/U/test/I"));
+    /// assert_eq!(Topic::Emergency.get(&Namespace::new("GroupH")), String::from("GroupH/Emergency/I"));
+    /// ```
+    pub fn get(self, ns: &Namespace) -> String {
+        match self {
+            Topic::HostI => format!("{}/Hosts/U/{}/I", ns.root, ns.host),
+            Topic::HostS(val) => format!("{}/Hosts/U/{}/S/{}", ns.root, ns.host, val),
+            Topic::VehicleI(val) => format!("{}/Vehicles/U/{}/I", ns.root, val),
+            Topic::VehicleS(val) => format!("{}/Vehicles/U/{}/S", ns.root, val),
+            Topic::Relay(val) => format!("{}/Relay/{}", ns.group, val),
+            Topic::VehicleE(val0, val1) => format!("{}/Vehicles/U/{}/E/{}", ns.root, val0, val1),
+            Topic::SpeedE(val) => format!("{}/Vehicles/U/{}/E/speed", ns.root, val),
+            Topic::Emergency => format!("{}/Emergency/I", ns.group),
+            Topic::VehicleEmergency(val) => format!("{}/Emergency/Vehicles/{}/I", ns.group, val),
+            Topic::GroupEmergency(val) => format!("{}/Emergency/Groups/{}/I", ns.group, val),
+            Topic::Zone => format!("{}/Zone/I", ns.group),
+            Topic::Presence(val) => format!("{}/Presence/{}", ns.group, val),
+            Topic::Decisions => format!("{}/Decisions/E", ns.group),
+            Topic::Rejected => format!("{}/Rejected/E", ns.group),
+        }
+    }
+
+    /// Parses a topic string of the given namespace back into a Topic, borrowing the values from the string. See OwnedTopic::parse for a Topic that outlives the string.
+    ///
+    /// Returns None for topics that aren't used by the project. Vehicle speed events are returned as VehicleE, and a relayed topic is only recognised if the inner topic is.
+    /// # Example
+    /// ```
+    /// use pc_mqtt_rs::{Namespace, Topic};
+    ///
+    /// let ns = Namespace::default();
+    /// let topic = Topic::parse("GroupG/Relay/Anki/Vehicles/U/test/I", &ns).unwrap();
+    /// assert_eq!(topic, Topic::Relay("Anki/Vehicles/U/test/I"));
+    /// assert_eq!(topic.relayed(&ns), Some(Topic::VehicleI("test")));
+    /// assert_eq!(Topic::parse("Anki/Vehicles/U/test/E/track", &ns), Some(Topic::VehicleE("test", "track")));
+    /// assert_eq!(Topic::parse("Anki/Vehicles/U", &ns), None);
+    /// assert_eq!(Topic::parse("GroupH/Zone/I", &ns), None);
+    /// assert_eq!(Topic::parse("GroupG/Emergency/Vehicles/test/I", &ns), Some(Topic::VehicleEmergency("test")));
+    /// ```
+    pub fn parse(topic: &'a str, ns: &Namespace) -> Option<Topic<'a>> {
+        if let Some(rest) = topic.strip_prefix(&format!("{}/", ns.group)) {
+            if let Some(inner) = rest.strip_prefix("Relay/") {
+                return Topic::parse(inner, ns).map(|_| Topic::Relay(inner));
+            }
+            let single = |val: &'a str| !val.is_empty() && !val.contains('/');
+            if let Some(val) = rest
+                .strip_prefix("Emergency/Vehicles/")
+                .and_then(|val| val.strip_suffix("/I"))
+            {
+                return single(val).then_some(Topic::VehicleEmergency(val));
+            }
+            if let Some(val) = rest
+                .strip_prefix("Emergency/Groups/")
+                .and_then(|val| val.strip_suffix("/I"))
+            {
+                return single(val).then_some(Topic::GroupEmergency(val));
+            }
+            if let Some(instance) = rest.strip_prefix("Presence/") {
+                return single(instance).then_some(Topic::Presence(instance));
+            }
+            return match rest {
+                "Emergency/I" => Some(Topic::Emergency),
+                "Zone/I" => Some(Topic::Zone),
+                "Decisions/E" => Some(Topic::Decisions),
+                "Rejected/E" => Some(Topic::Rejected),
+                _ => None,
+            };
+        }
+
+        if let Some(rest) = topic.strip_prefix(&format!("{}/Hosts/U/{}/", ns.root, ns.host)) {
+            if rest == "I" {
+                return Some(Topic::HostI);
+            }
+            return rest
+                .strip_prefix("S/")
+                .filter(|val| !val.is_empty())
+                .map(Topic::HostS);
+        }
+
+        let rest = topic.strip_prefix(&format!("{}/Vehicles/U/", ns.root))?;
+        match rest.split('/').collect::<Vec<&str>>().as_slice() {
+            [id, "I"] if !id.is_empty() => Some(Topic::VehicleI(id)),
+            [id, "S"] if !id.is_empty() => Some(Topic::VehicleS(id)),
+            [id, "E", event] if !id.is_empty() && !event.is_empty() => {
+                Some(Topic::VehicleE(id, event))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the inner topic of a Relay topic.
+    pub fn relayed(&self, ns: &Namespace) -> Option<Topic<'a>> {
+        match self {
+            Topic::Relay(inner) => Topic::parse(inner, ns),
+            _ => None,
+        }
+    }
+
+    /// Returns the vehicle ID of vehicle topics.
     /// # Example
     /// ```
     /// use pc_mqtt_rs::Topic;
     ///
-    /// assert_eq!(Topic::VehicleI("test").get(), String::from("Anki/Vehicles/U/test/I"));
+    /// assert_eq!(Topic::SpeedE("test").vehicle_id(), Some("test"));
+    /// assert_eq!(Topic::Zone.vehicle_id(), None);
     /// ```
-    pub fn get(self) -> String {
+    pub fn vehicle_id(&self) -> Option<&'a str> {
         match self {
-            Topic::HostI => String::from("Anki/Hosts/U/hyperdrive/I"),
-            Topic::HostS(val) => format!(r#"Anki/Hosts/U/hyperdrive/S/{}"#, val),
-            Topic::VehicleI(val) => format!(r#"Anki/Vehicles/U/{}/I"#, val),
-            Topic::VehicleS(val) => format!(r#"Anki/Vehicles/U/{}/S"#, val),
-            Topic::Relay(val) => format!(r#"GroupG/Relay/{}"#, val),
-            Topic::VehicleE(val0, val1) => format!(r#"Anki/Vehicles/U/{}/E/{}"#, val0, val1),
-            Topic::SpeedE(val) => format!(r#"Anki/Vehicles/U/{}/E/speed"#, val),
-            Topic::Emergency => String::from("GroupG/Emergency/I"),
-            Topic::Zone => String::from(r#"GroupG/Zone/I"#),
+            Topic::VehicleI(id)
+            | Topic::VehicleS(id)
+            | Topic::VehicleE(id, _)
+            | Topic::SpeedE(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// Owned version of Topic, holding its values as Strings so it can be kept after the topic string is gone.
+/// # Example
+/// ```
+/// use pc_mqtt_rs::{Namespace, OwnedTopic, Topic};
+///
+/// let ns = Namespace::default();
+/// let topic = OwnedTopic::parse(&String::from("GroupG/Relay/Anki/Vehicles/U/test/I"), &ns).unwrap();
+/// assert_eq!(topic, OwnedTopic::Relay(String::from("Anki/Vehicles/U/test/I")));
+/// assert_eq!(topic.as_topic().relayed(&ns), Some(Topic::VehicleI("test")));
+/// assert_eq!(OwnedTopic::from(Topic::VehicleE("test", "track")).as_topic().get(&ns), "Anki/Vehicles/U/test/E/track");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OwnedTopic {
+    HostI,
+    HostS(String),
+    VehicleI(String),
+    VehicleS(String),
+    VehicleE(String, String),
+    Relay(String),
+    SpeedE(String),
+    Emergency,
+    VehicleEmergency(String),
+    GroupEmergency(String),
+    Zone,
+    Presence(String),
+    Decisions,
+    Rejected,
+}
+
+impl OwnedTopic {
+    /// Same as Topic::parse, copying the values out of the string.
+    pub fn parse(topic: &str, ns: &Namespace) -> Option<OwnedTopic> {
+        Topic::parse(topic, ns).map(OwnedTopic::from)
+    }
+
+    /// Borrows the values as a Topic, to format or match it.
+    pub fn as_topic(&self) -> Topic<'_> {
+        match self {
+            OwnedTopic::HostI => Topic::HostI,
+            OwnedTopic::HostS(val) => Topic::HostS(val),
+            OwnedTopic::VehicleI(val) => Topic::VehicleI(val),
+            OwnedTopic::VehicleS(val) => Topic::VehicleS(val),
+            OwnedTopic::VehicleE(val0, val1) => Topic::VehicleE(val0, val1),
+            OwnedTopic::Relay(val) => Topic::Relay(val),
+            OwnedTopic::SpeedE(val) => Topic::SpeedE(val),
+            OwnedTopic::Emergency => Topic::Emergency,
+            OwnedTopic::VehicleEmergency(val) => Topic::VehicleEmergency(val),
+            OwnedTopic::GroupEmergency(val) => Topic::GroupEmergency(val),
+            OwnedTopic::Zone => Topic::Zone,
+            OwnedTopic::Presence(val) => Topic::Presence(val),
+            OwnedTopic::Decisions => Topic::Decisions,
+            OwnedTopic::Rejected => Topic::Rejected,
+        }
+    }
+}
+
+impl From<Topic<'_>> for OwnedTopic {
+    fn from(topic: Topic) -> Self {
+        match topic {
+            Topic::HostI => OwnedTopic::HostI,
+            Topic::HostS(val) => OwnedTopic::HostS(val.to_string()),
+            Topic::VehicleI(val) => OwnedTopic::VehicleI(val.to_string()),
+            Topic::VehicleS(val) => OwnedTopic::VehicleS(val.to_string()),
+            Topic::VehicleE(val0, val1) => OwnedTopic::VehicleE(val0.to_string(), val1.to_string()),
+            Topic::Relay(val) => OwnedTopic::Relay(val.to_string()),
+            Topic::SpeedE(val) => OwnedTopic::SpeedE(val.to_string()),
+            Topic::Emergency => OwnedTopic::Emergency,
+            Topic::VehicleEmergency(val) => OwnedTopic::VehicleEmergency(val.to_string()),
+            Topic::GroupEmergency(val) => OwnedTopic::GroupEmergency(val.to_string()),
+            Topic::Zone => OwnedTopic::Zone,
+            Topic::Presence(val) => OwnedTopic::Presence(val.to_string()),
+            Topic::Decisions => OwnedTopic::Decisions,
+            Topic::Rejected => OwnedTopic::Rejected,
         }
     }
 }
@@ -1,74 +1,384 @@
 //! Utility functions that are removed from main.rs.
-use crate::{ClientWrapper, Payload, Topic};
+use crate::{
+    ClientWrapper, ControllerHandle, Error, Namespace, Payload, StopToken, Topic, VehicleList,
+    VehicleStatus,
+};
 use rumqttc::Publish;
-use std::{io, sync::mpsc::Receiver, thread, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
 
-/// Sends Connect(true) to each vehicle.
-pub fn connect_vehicles(client: &mut ClientWrapper, vehicle_list: &Vec<String>) {
+/// Settings of connect_vehicles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connecting {
+    /// How long to wait for the vehicles to report they are connected, 3 seconds by default.
+    pub timeout: Duration,
+    /// How many times Connect(true) is sent again to the vehicles that didn't report, 2 by default.
+    pub retries: u32,
+}
+
+impl Default for Connecting {
+    fn default() -> Self {
+        Connecting {
+            timeout: Duration::from_secs(3),
+            retries: 2,
+        }
+    }
+}
+
+/// Result of connect_vehicles for a single vehicle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectedVehicle {
+    pub id: String,
+    /// Status reporting the vehicle as connected, None if it never did.
+    pub status: Option<VehicleStatus>,
+    /// How many times Connect(true) was sent.
+    pub attempts: u32,
+}
+
+impl ConnectedVehicle {
+    pub fn is_connected(&self) -> bool {
+        self.status.is_some()
+    }
+}
+
+/// Sends Connect(true) to each vehicle and waits for their status topic to report them as connected.
+///
+/// The messages are sent straight to the vehicles, so the relay doesn't need to run yet. Vehicles that don't report within settings.timeout are sent Connect(true) again, up to settings.retries times.
+///
+/// The receiver should be the one of the client's connection, messages on other topics are skipped. Returns a result for every vehicle in the order of vehicle_list, or an error if the client can't be used anymore or a status can't be parsed.
+pub fn connect_vehicles(
+    client: &mut ClientWrapper,
+    ns: &Namespace,
+    receiver: &Receiver<Publish>,
+    vehicle_list: &[String],
+    settings: &Connecting,
+) -> Result<Vec<ConnectedVehicle>, Error> {
+    let mut vehicles: Vec<ConnectedVehicle> = vehicle_list
+        .iter()
+        .map(|id| ConnectedVehicle {
+            id: id.clone(),
+            status: None,
+            attempts: 0,
+        })
+        .collect();
+    for vehicle in &vehicles {
+        client.subscribe_with_retry(&Topic::VehicleS(&vehicle.id).get(ns), 5)?;
+    }
+
+    for _ in 0..=settings.retries {
+        for vehicle in vehicles.iter_mut().filter(|v| !v.is_connected()) {
+            client.publish_with_retry(
+                &Topic::VehicleI(&vehicle.id).get(ns),
+                &Payload::Connect(true).get(),
+                5,
+            )?;
+            vehicle.attempts += 1;
+        }
+
+        // Stops once every vehicle is connected or the time is up
+        let deadline = Instant::now() + settings.timeout;
+        while vehicles.iter().any(|v| !v.is_connected()) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let received = receive(receiver, remaining, |message| {
+                match Topic::parse(&message.topic, ns) {
+                    Some(Topic::VehicleS(id)) => {
+                        let status = serde_json::from_slice::<VehicleStatus>(&message.payload)?;
+                        Ok(status.connected.then(|| (id.to_string(), status)))
+                    }
+                    _ => Ok(None),
+                }
+            })?;
+            let Some((id, status)) = received else { break };
+            if let Some(vehicle) = vehicles.iter_mut().find(|v| v.id == id) {
+                vehicle.status = Some(status);
+            }
+        }
+
+        if vehicles.iter().all(|v| v.is_connected()) {
+            break;
+        }
+    }
+
+    for vehicle in &vehicles {
+        client.unsubscribe(&Topic::VehicleS(&vehicle.id).get(ns))?;
+    }
+    Ok(vehicles)
+}
+
+/// Sends Connect(false) to each vehicle, straight to the vehicles like connect_vehicles.
+pub fn disconnect_vehicles(
+    client: &mut ClientWrapper,
+    ns: &Namespace,
+    vehicle_list: &[String],
+) -> Result<(), Error> {
     for vehicle in vehicle_list {
-        client.publish(
-            &Topic::Relay(&Topic::VehicleI(vehicle).get()).get(),
-            &Payload::Connect(true).get(),
-        );
+        client.publish_with_retry(
+            &Topic::VehicleI(vehicle).get(ns),
+            &Payload::Connect(false).get(),
+            5,
+        )?;
+    }
+    Ok(())
+}
+
+/// Settings of discover_vehicles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovery {
+    /// How long to wait for the host to answer a discover message, 2 seconds by default.
+    pub timeout: Duration,
+    /// How many times the discover message is sent again if the host doesn't answer, 2 by default.
+    pub retries: u32,
+    /// How long to wait for the status of the discovered vehicles, 500ms by default.
+    pub status_timeout: Duration,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            timeout: Duration::from_secs(2),
+            retries: 2,
+            status_timeout: Duration::from_millis(500),
+        }
     }
 }
 
-/// Asks for discovered vehicle IDs and prints them to stdout.
+/// A vehicle found by discover_vehicles, with its status if the vehicle published one in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredVehicle {
+    pub id: String,
+    pub status: Option<VehicleStatus>,
+}
+
+/// Asks the host for the discovered vehicles and collects their status.
+///
+/// The receiver should be the one of the client's connection. Messages on other topics are skipped, and the discover message is sent again up to settings.retries times if the host doesn't answer within settings.timeout.
+///
+/// The status topics of the vehicles are then listened to for settings.status_timeout, which is usually enough to get the retained status published by the host.
 pub fn discover_vehicles(
     client: &mut ClientWrapper,
+    ns: &Namespace,
     receiver: &Receiver<Publish>,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    println!("No vehicles specified. \nDiscovering vehicles...");
-    client.subscribe(&Topic::HostS("vehicles").get());
-    client.publish(&Topic::HostI.get(), &Payload::Discover(true).get());
-
-    let received_payload = receiver.recv()?.payload;
-    let payload: serde_json::Value = serde_json::from_slice(&received_payload)?;
-    let available_vehicles = payload["value"]
-        .as_array()
-        .ok_or("None error")?
-        .iter()
-        .map(|v| v.as_str().expect("Should be valid UTF-8").to_string())
-        .collect::<Vec<String>>();
+    settings: &Discovery,
+) -> Result<Vec<DiscoveredVehicle>, Box<dyn std::error::Error>> {
+    let list_topic = Topic::HostS("vehicles").get(ns);
+    client.subscribe_with_retry(&list_topic, 5)?;
+
+    let mut vehicle_ids = None;
+    for _ in 0..=settings.retries {
+        client.publish_with_retry(&Topic::HostI.get(ns), &Payload::Discover(true).get(), 5)?;
+        vehicle_ids = receive(receiver, settings.timeout, |message| {
+            match Topic::parse(&message.topic, ns) {
+                Some(Topic::HostS("vehicles")) => {
+                    let list = serde_json::from_slice::<VehicleList>(&message.payload)?;
+                    Ok(Some(list.value))
+                }
+                _ => Ok(None),
+            }
+        })?;
+        if vehicle_ids.is_some() {
+            break;
+        }
+    }
 
-    client.publish(&Topic::HostI.get(), &Payload::Discover(false).get());
+    client.publish_with_retry(&Topic::HostI.get(ns), &Payload::Discover(false).get(), 5)?;
+    client.unsubscribe(&list_topic)?;
+    let vehicle_ids = vehicle_ids.ok_or_else(|| {
+        format!(
+            "the host didn't answer after {} discover messages",
+            settings.retries + 1
+        )
+    })?;
 
-    // Test if necessary (TODO)
-    std::thread::sleep(Duration::from_millis(30));
-    Ok(available_vehicles)
+    let mut vehicles: Vec<DiscoveredVehicle> = vehicle_ids
+        .into_iter()
+        .map(|id| DiscoveredVehicle { id, status: None })
+        .collect();
+    for vehicle in &vehicles {
+        client.subscribe_with_retry(&Topic::VehicleS(&vehicle.id).get(ns), 5)?;
+    }
+
+    // Stops once every vehicle has a status or the time is up
+    let deadline = Instant::now() + settings.status_timeout;
+    while vehicles.iter().any(|v| v.status.is_none()) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let received = receive(receiver, remaining, |message| {
+            match Topic::parse(&message.topic, ns) {
+                Some(Topic::VehicleS(id)) => {
+                    let status = serde_json::from_slice::<VehicleStatus>(&message.payload)?;
+                    Ok(Some((id.to_string(), status)))
+                }
+                _ => Ok(None),
+            }
+        })?;
+        let Some((id, status)) = received else { break };
+        if let Some(vehicle) = vehicles.iter_mut().find(|v| v.id == id) {
+            vehicle.status = Some(status);
+        }
+    }
+
+    for vehicle in &vehicles {
+        client.unsubscribe(&Topic::VehicleS(&vehicle.id).get(ns))?;
+    }
+    Ok(vehicles)
+}
+
+/// Waits up to timeout for a message accepted by select, skipping the others.
+///
+/// Returns None on timeout, and an error if the connection loop has stopped or select fails.
+fn receive<T>(
+    receiver: &Receiver<Publish>,
+    timeout: Duration,
+    mut select: impl FnMut(&Publish) -> Result<Option<T>, Error>,
+) -> Result<Option<T>, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(message) => {
+                if let Some(selected) = select(&message)? {
+                    return Ok(Some(selected));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => return Err(Error::Disconnected),
+        }
+    }
 }
 
 /// Blocks thread and publishes emergency messages on the keypress of enter.
-pub fn blocking_emergency_handler(client: &mut ClientWrapper) {
+///
+/// An empty line toggles the emergency state of the whole fleet, a vehicle ID toggles the one of that vehicle, and "@name" the one of the group of that name.
+///
+/// The messages are retained, so they also replace a stop published as the relay's Last Will. Every toggle starts from the last state published by anyone, received on rx once the emergency topics are subscribed to through the client.
+pub fn blocking_emergency_handler(
+    client: &mut ClientWrapper,
+    rx: &Receiver<Publish>,
+    ns: &Namespace,
+) {
     let mut input = String::new();
-    let mut state = false;
+    let mut states: HashMap<String, bool> = HashMap::new();
+
+    for topic in [
+        Topic::Emergency,
+        Topic::VehicleEmergency("+"),
+        Topic::GroupEmergency("+"),
+    ] {
+        if let Err(e) = client.subscribe_with_retry(&topic.get(ns), 5) {
+            println!("main: Failed to subscribe to the emergency topics: {}", e);
+            return;
+        }
+    }
 
-    println!("main: Press enter to toggle emergency state");
+    println!("main: Press enter to toggle emergency state, or type a vehicle ID or @group first");
     loop {
-        io::stdin()
+        input.clear();
+        // Stops at the end of the input, for example when not run from a terminal
+        if io::stdin()
             .read_line(&mut input)
-            .expect("Failed to read line");
-        state = !state;
-        client.publish(&Topic::Emergency.get(), &Payload::Emergency(state).get());
+            .expect("Failed to read line")
+            == 0
+        {
+            return;
+        }
+        let topic = match input.trim() {
+            "" => Topic::Emergency,
+            target => match target.strip_prefix('@') {
+                Some(group) => Topic::GroupEmergency(group),
+                None => Topic::VehicleEmergency(target),
+            },
+        }
+        .get(ns);
+
+        // Emergency messages received since the last toggle, the retained ones first
+        for message in rx.try_iter() {
+            let emergency = matches!(
+                Topic::parse(&message.topic, ns),
+                Some(Topic::Emergency | Topic::VehicleEmergency(_) | Topic::GroupEmergency(_))
+            );
+            if let (true, Ok(Payload::Emergency(state))) =
+                (emergency, Payload::parse(&message.payload))
+            {
+                states.insert(message.topic, state);
+            }
+        }
+
+        let state = states.entry(topic.clone()).or_insert(false);
+        *state = !*state;
+        println!(
+            "main: {} {}",
+            if *state { "Stopping" } else { "Releasing" },
+            input.trim()
+        );
+        if let Err(e) = client.publish_retained(&topic, &Payload::Emergency(*state).get()) {
+            println!("main: Failed to publish emergency message: {}", e);
+            if e.is_fatal() {
+                return;
+            }
+        }
     }
 }
 
-/// Sets up a handler to disconnect vehicles on CTRL+C.
-pub fn set_ctrlc_handler(client: &ClientWrapper, vehicle_list: &[String]) {
-    let mut cloned_client = client.arc_clone();
-    let cloned_vehicle_list = vehicle_list.to_owned();
+/// Sets up a handler stopping the token on CTRL+C or SIGTERM, so the main thread can run shutdown.
+///
+/// A second signal exits right away, in case the shutdown hangs.
+pub fn set_shutdown_handler(token: &StopToken) {
+    let token = token.clone();
     ctrlc::set_handler(move || {
-        println!("Exiting...");
-
-        for vehicle in &cloned_vehicle_list {
-            cloned_client.publish(
-                &Topic::Relay(&Topic::VehicleI(vehicle).get()).get(),
-                &Payload::Connect(false).get(),
-            );
+        if token.is_stopped() {
+            println!("Exiting without shutting down");
+            std::process::exit(1);
         }
-
-        thread::sleep(Duration::from_secs_f32(0.1));
-        std::process::exit(0);
+        println!("Shutting down...");
+        token.stop();
     })
     .expect("Error setting Ctrl-C handler");
 }
+
+/// Speed 0 sent to every vehicle on shutdown, braking harder than the default.
+const STOP_ACCELERATION: u16 = 2000;
+
+/// Stops the controllers and the vehicles in an order that leaves every vehicle standing still and disconnected:
+/// 1. The steering controllers are stopped and joined, so no new commands are sent.
+/// 2. The other controllers, such as the relay and track, are stopped and joined, so no speed is overridden anymore.
+/// 3. Every vehicle is sent Speed(0) and then Connect(false), straight to the vehicles.
+/// 4. The client sends its pending messages and disconnects, waiting up to timeout.
+///
+/// Controllers that panicked are reported and don't interrupt the sequence. Returns an error if the messages couldn't be sent.
+pub fn shutdown(
+    client: &mut ClientWrapper,
+    ns: &Namespace,
+    vehicle_list: &[String],
+    steering: Vec<ControllerHandle>,
+    others: Vec<ControllerHandle>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    for controllers in [steering, others] {
+        for controller in &controllers {
+            controller.stop();
+        }
+        for controller in controllers {
+            let name = controller.name().to_string();
+            if controller.join().is_err() {
+                println!("main: {} controller panicked", name);
+            }
+        }
+    }
+
+    for vehicle in vehicle_list {
+        client.publish_with_retry(
+            &Topic::VehicleI(vehicle).get(ns),
+            &Payload::Speed(0, STOP_ACCELERATION).get(),
+            5,
+        )?;
+    }
+    disconnect_vehicles(client, ns, vehicle_list)?;
+
+    if !client.close(timeout)? {
+        println!("main: Pending messages not sent before the timeout");
+    }
+    Ok(())
+}
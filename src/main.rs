@@ -1,57 +1,475 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use pc_mqtt_rs::*;
-use std::{thread, time::Duration};
+use rumqttc::Publish;
+use std::{path::PathBuf, sync::mpsc::Receiver, thread, time::Duration};
+
+/// Controls Anki Overdrive vehicles through the hyperdrive MQTT host.
+///
+/// Broker settings are read from the PC_MQTT_* environment variables (see BrokerConfig::from_env) or from a TOML file, and can be overridden with the flags below.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    broker: BrokerArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Broker options")]
+struct BrokerArgs {
+    /// TOML file with the broker settings, used instead of the environment variables.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Broker host name or IP address.
+    #[arg(long, global = true)]
+    host: Option<String>,
+    /// Broker port.
+    #[arg(long, global = true)]
+    port: Option<u16>,
+    /// Broker username, requires a password.
+    #[arg(long, global = true, requires = "password")]
+    username: Option<String>,
+    /// Broker password, requires a username.
+    #[arg(long, global = true, requires = "username")]
+    password: Option<String>,
+    /// Group prefix of the relay, emergency and zone topics.
+    #[arg(long, global = true)]
+    group: Option<String>,
+    /// First part of the client IDs.
+    #[arg(long, global = true)]
+    client_prefix: Option<String>,
+    /// Last part of the client IDs, generated from the hostname and process ID by default.
+    #[arg(long, global = true)]
+    instance: Option<String>,
+}
+
+impl BrokerArgs {
+    /// Broker settings from the config file, the scenario or the environment, in that order, overridden by the flags.
+    fn broker(
+        &self,
+        scenario: Option<&Scenario>,
+    ) -> Result<BrokerConfig, Box<dyn std::error::Error>> {
+        let mut broker = match (&self.config, scenario.and_then(|s| s.broker.clone())) {
+            (Some(path), _) => BrokerConfig::from_file(path)?,
+            (None, Some(broker)) => broker,
+            (None, None) => BrokerConfig::from_env()?,
+        };
+        if let Some(host) = &self.host {
+            broker.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            broker.port = port;
+        }
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            broker = broker.with_credentials(username, password);
+        }
+        if let Some(prefix) = &self.client_prefix {
+            broker.client_prefix = prefix.clone();
+        }
+        if let Some(instance) = &self.instance {
+            broker.instance = Some(instance.clone());
+        }
+        Ok(broker)
+    }
+
+    /// Namespace from the scenario or the environment, with the group overridden by the flag.
+    fn namespace(&self, scenario: Option<&Scenario>) -> Namespace {
+        let mut namespace = match scenario {
+            Some(scenario) => scenario.namespace.clone(),
+            None => Namespace::from_env(),
+        };
+        if let Some(group) = &self.group {
+            namespace.group = group.clone();
+        }
+        namespace
+    }
+}
+
+#[derive(Args)]
+struct VehicleArgs {
+    /// Vehicle IDs, comma separated or repeated.
+    #[arg(short, long = "vehicle", value_delimiter = ',', required = true)]
+    vehicles: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the IDs and status of the vehicles found by the host.
+    Discover {
+        /// Milliseconds to wait for the host to answer.
+        #[arg(long, default_value_t = 2000)]
+        timeout_ms: u64,
+        /// How many times to ask again if the host doesn't answer.
+        #[arg(long, default_value_t = 2)]
+        retries: u32,
+    },
+    /// Connect the vehicles and start the controllers.
+    Run {
+        #[command(flatten)]
+        vehicles: VehicleArgs,
+        /// Velocities the speed controller iterates over.
+        #[arg(
+            long,
+            value_delimiter = ',',
+            allow_negative_numbers = true,
+            default_value = "500"
+        )]
+        speeds: Vec<i16>,
+        /// Lane offsets the lane controller iterates over.
+        #[arg(
+            long,
+            value_delimiter = ',',
+            allow_negative_numbers = true,
+            default_value = "0"
+        )]
+        lanes: Vec<i16>,
+        /// Track IDs where vehicles are slowed down to velocity 200.
+        #[arg(long, value_delimiter = ',', default_value = "20,4,21")]
+        slow_tracks: Vec<u64>,
+        /// Controllers to start.
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "relay,blink,speed,lane,track"
+        )]
+        controllers: Vec<Controller>,
+    },
+    /// Connect the vehicles and start the controllers described in a TOML or JSON scenario file.
+    Scenario { file: PathBuf },
+    /// Set or release the emergency stop of the whole fleet, a vehicle or a group of vehicles.
+    Emergency {
+        state: State,
+        /// Only stop or release this vehicle.
+        #[arg(long, conflicts_with = "vehicle_group")]
+        vehicle: Option<String>,
+        /// Only stop or release this group of vehicles, as named in the relay settings of the scenario.
+        #[arg(long)]
+        vehicle_group: Option<String>,
+    },
+    /// Connect the vehicles without starting any controller.
+    Connect(VehicleArgs),
+    /// Disconnect the vehicles.
+    Disconnect(VehicleArgs),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Controller {
+    Relay,
+    Blink,
+    Speed,
+    Lane,
+    Track,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum State {
+    On,
+    Off,
+}
+
+impl Command {
+    /// Loads the scenario of the command, the run command being turned into one with every controller set by its flags.
+    fn scenario(&self) -> Result<Option<Scenario>, Box<dyn std::error::Error>> {
+        match self {
+            Command::Scenario { file } => Ok(Some(Scenario::from_file(file)?)),
+            Command::Run {
+                vehicles,
+                speeds,
+                lanes,
+                slow_tracks,
+                controllers,
+            } => {
+                let enabled = |controller| controllers.contains(&controller);
+                let scenario = Scenario {
+                    broker: None,
+                    namespace: Namespace::from_env(),
+                    vehicles: vehicles
+                        .vehicles
+                        .iter()
+                        .map(|id| VehicleConfig {
+                            id: id.clone(),
+                            name: None,
+                        })
+                        .collect(),
+                    controllers: Controllers {
+                        relay: enabled(Controller::Relay).then(RelayConfig::default),
+                        blink: enabled(Controller::Blink).then(BlinkConfig::default),
+                        speed: enabled(Controller::Speed).then(|| SpeedConfig {
+                            velocities: speeds.clone(),
+                            ..Default::default()
+                        }),
+                        lane: enabled(Controller::Lane).then(|| LaneConfig {
+                            offsets: lanes.clone(),
+                            ..Default::default()
+                        }),
+                        track: enabled(Controller::Track).then(|| TrackConfig {
+                            slow_tracks: slow_tracks.clone(),
+                        }),
+                    },
+                };
+                // Same validation as a scenario file, so an empty list is reported instead of starting
+                Ok(Some(scenario.validate()?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Connects the vehicles and prints the result for each of them, returning how many didn't confirm.
+fn connect(
+    client: &mut ClientWrapper,
+    namespace: &Namespace,
+    rx: &Receiver<Publish>,
+    vehicles: &[VehicleConfig],
+) -> Result<usize, Error> {
+    let ids: Vec<String> = vehicles.iter().map(|v| v.id.clone()).collect();
+    let results = connect_vehicles(client, namespace, rx, &ids, &Connecting::default())?;
+
+    for (vehicle, result) in vehicles.iter().zip(&results) {
+        if result.is_connected() {
+            println!("Connected {}", vehicle.label());
+        } else {
+            println!(
+                "Failed to connect {} after {} attempts",
+                vehicle.label(),
+                result.attempts
+            );
+        }
+    }
+    Ok(results.iter().filter(|r| !r.is_connected()).count())
+}
+
+/// Connects the vehicles and starts the controllers of the scenario.
+///
+/// Vehicles that don't confirm the connection are reported, the controllers are started anyway. If a step fails, the controllers already started and the vehicles are stopped before returning the error.
+fn start(
+    scenario: &Scenario,
+    client: &mut ClientWrapper,
+    rx: &Receiver<Publish>,
+    broker: &BrokerConfig,
+    namespace: &Namespace,
+) -> Result<Supervisor, Box<dyn std::error::Error>> {
+    let mut supervisor = Supervisor::new(broker, namespace);
+    let started = connect(client, namespace, rx, &scenario.vehicles)
+        .and_then(|_| add_controllers(&mut supervisor, scenario, broker));
+    if let Err(e) = started {
+        if let Err(shutdown_error) =
+            supervisor.shutdown(client, &scenario.vehicle_ids(), CLOSE_TIMEOUT)
+        {
+            eprintln!("Failed to stop the vehicles: {}", shutdown_error);
+        }
+        return Err(e.into());
+    }
+    Ok(supervisor)
+}
+
+/// Starts the controllers configured in the scenario.
+fn add_controllers(
+    supervisor: &mut Supervisor,
+    scenario: &Scenario,
+    broker: &BrokerConfig,
+) -> Result<(), Error> {
+    let vehicles = scenario.vehicle_ids();
+    let controllers = &scenario.controllers;
+
+    if let Some(relay) = &controllers.relay {
+        let mut controller = Relay::new(&vehicles).with_policy(relay.policy.build());
+        if relay.require_sender {
+            let mut senders: Vec<String> = controllers
+                .names()
+                .into_iter()
+                .map(|name| broker.client_id(name))
+                .collect();
+            senders.extend(relay.senders.iter().cloned());
+            controller = controller.with_senders(&senders, relay.ignore_unknown);
+        }
+        for (name, members) in &relay.groups {
+            controller = controller.with_group(name, members);
+        }
+        supervisor.add(controller)?;
+    }
+
+    if let Some(blink) = &controllers.blink {
+        let mut controller = Blink::new(&vehicles)
+            .with_interval(Duration::from_millis(blink.interval_ms))
+            .with_alternate(blink.alternate);
+        if let Some(pattern) = blink.pattern {
+            controller = controller.with_pattern(pattern);
+        }
+        if let Some(pattern) = blink.zone {
+            controller = controller.with_zone_pattern(pattern);
+        }
+        if let Some(pattern) = blink.emergency {
+            controller = controller.with_emergency_pattern(pattern);
+        }
+        // The groups stopped through the relay show the emergency pattern as well
+        for (name, members) in controllers.relay.iter().flat_map(|relay| &relay.groups) {
+            controller = controller.with_group(name, members);
+        }
+        supervisor.add(controller)?;
+    }
+    if let Some(speed) = &controllers.speed {
+        let mut controller = Speed::new(&speed.velocities, &vehicles)
+            .with_interval(Duration::from_millis(speed.interval_ms))
+            .with_acceleration(speed.acceleration);
+        for (vehicle, program) in &speed.programs {
+            controller = controller.with_program(vehicle, program.clone());
+        }
+        supervisor.add(controller)?;
+    }
+    if let Some(lane) = &controllers.lane {
+        let mut controller = Lane::new(&lane.offsets, &vehicles)
+            .with_interval(Duration::from_millis(lane.interval_ms))
+            .with_lane_change(lane.velocity, lane.acceleration);
+        for (vehicle, schedule) in &lane.schedules {
+            controller = controller.with_schedule(vehicle, schedule.clone());
+        }
+        supervisor.add(controller)?;
+    }
+    if let Some(track) = &controllers.track {
+        supervisor.add(Track::new(&vehicles, &track.slow_tracks))?;
+    }
+    Ok(())
+}
+
+/// How long to wait for the pending messages to be sent before exiting.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Prints the warnings and errors of the library to stderr.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // CONFIG START HERE
-    let vehicle_list: Vec<String> = vec![
-        //String::from("f4c22c6c0382"),
-        //String::from("cec233dec1cb"),
-        //String::from("f2e85f2f5770"),
-        String::from("d98ebab7c206"),
-        //String::from("c60ee9d05225"),
-        //String::from("c40caf091413"),
-        //String::from("d11d2fea5c74"),
-    ];
-
-    // For steering and track demonstration
-    //let speed_list = vec![300, 400, 500];
-    //let lane_list = vec![-20, 0];
-    //let slow_tracks = vec![];
-
-    // For personal addition demonstration
-    let speed_list = vec![500];
-    let lane_list = vec![0];
-    let slow_tracks = vec![20, 4, 21];
-    // CONFIG END HERE
+    log::set_logger(&LOGGER).map_err(|e| e.to_string())?;
+    log::set_max_level(log::LevelFilter::Warn);
+    let cli = Cli::parse();
+    let scenario = cli.command.scenario()?;
+    let broker = cli.broker.broker(scenario.as_ref())?;
+    let namespace = cli.broker.namespace(scenario.as_ref());
+
+    // Client IDs of this instance, which must not be used by another one
+    let mut names = vec!["main"];
+    if let Some(scenario) = &scenario {
+        names.extend(scenario.controllers.names());
+    }
+    let presence = Presence::new(&broker, &names);
+    let in_use = presence.collisions(&broker, &namespace, PRESENCE_TIMEOUT)?;
+    if !in_use.is_empty() {
+        return Err(format!(
+            "client IDs already in use: {}, choose another --instance",
+            in_use.join(", ")
+        )
+        .into());
+    }
 
     // Shared MQTT client for helper function such as discover_vehicles, connect_vehicles, etc.
-    let (mut client, connection) = Mqtt::new("groupg_main");
-    // Channel receiver to receive messages from a connection loop. This specific one is only used by the discover_vehicles function.
+    let (mut client, connection) = Mqtt::with_last_will(
+        &broker.client_id("main"),
+        &broker,
+        Some(presence.last_will(&namespace)),
+    )?;
+    // Channel receiver to receive messages from a connection loop. It is used by the discover_vehicles and connect_vehicles functions and the emergency handler.
     let rx = connection.start_loop();
+    presence.announce(&mut client, &namespace)?;
 
-    // Discover and print vehicles IDs if none are specified
-    if vehicle_list.is_empty() {
-        for vehicle in discover_vehicles(&mut client, &rx)? {
-            println!("  {}", vehicle);
-        }
-        std::process::exit(0);
-    }
+    if let Some(scenario) = scenario {
+        // CTRL+C and SIGTERM handler, set before starting anything so no signal is missed
+        let exit = StopToken::new();
+        set_shutdown_handler(&exit);
 
-    // Start relay first to avoid lost connect messages
-    let _relay = Relay::new(&vehicle_list).run();
-    thread::sleep(Duration::from_millis(30)); // Hack for lost connect messages (TODO)
+        let supervisor = start(&scenario, &mut client, &rx, &broker, &namespace)?;
 
-    connect_vehicles(&mut client, &vehicle_list);
-    let _blink = Blink::new(&vehicle_list).run();
-    let _speed = Speed::new(&speed_list, &vehicle_list).run();
-    let _lane = Lane::new(&lane_list, &vehicle_list).run();
-    let _track = Track::new(&vehicle_list, &slow_tracks).run();
+        // Publish emergency messages on keypresses of enter, in its own thread as reading stdin can't be interrupted
+        let mut emergency_client = client.arc_clone();
+        let emergency_namespace = namespace.clone();
+        thread::spawn(move || {
+            blocking_emergency_handler(&mut emergency_client, &rx, &emergency_namespace)
+        });
 
-    // CTRL+C handler to disconnect vehicles on exit
-    set_ctrlc_handler(&client, &vehicle_list);
+        exit.wait();
+        presence.withdraw(&mut client, &namespace)?;
+        supervisor.shutdown(&mut client, &scenario.vehicle_ids(), CLOSE_TIMEOUT)?;
+        return Ok(());
+    }
 
-    // Block thread and publish emergency messages on keypresses of enter
-    blocking_emergency_handler(&mut client);
+    match cli.command {
+        Command::Discover {
+            timeout_ms,
+            retries,
+        } => {
+            println!("Discovering vehicles...");
+            let settings = Discovery {
+                timeout: Duration::from_millis(timeout_ms),
+                retries,
+                ..Default::default()
+            };
+            for vehicle in discover_vehicles(&mut client, &namespace, &rx, &settings)? {
+                match vehicle.status {
+                    Some(status) => println!(
+                        "  {}  connected: {}, battery: {}, model: {}",
+                        vehicle.id,
+                        status.connected,
+                        status
+                            .battery
+                            .map_or(String::from("?"), |b| format!("{}%", b)),
+                        status.model.as_deref().unwrap_or("?"),
+                    ),
+                    None => println!("  {}  no status", vehicle.id),
+                }
+            }
+        }
+        Command::Emergency {
+            state,
+            vehicle,
+            vehicle_group,
+        } => {
+            let topic = match (&vehicle, &vehicle_group) {
+                (Some(vehicle), _) => Topic::VehicleEmergency(vehicle),
+                (None, Some(group)) => Topic::GroupEmergency(group),
+                (None, None) => Topic::Emergency,
+            };
+            client.publish_retained(
+                &topic.get(&namespace),
+                &Payload::Emergency(state == State::On).get(),
+            )?;
+        }
+        Command::Connect(VehicleArgs { vehicles }) => {
+            let vehicles: Vec<VehicleConfig> = vehicles
+                .into_iter()
+                .map(|id| VehicleConfig { id, name: None })
+                .collect();
+            let failed = connect(&mut client, &namespace, &rx, &vehicles)?;
+            if failed > 0 {
+                return Err(format!("{} vehicles didn't connect", failed).into());
+            }
+        }
+        Command::Disconnect(VehicleArgs { vehicles }) => {
+            disconnect_vehicles(&mut client, &namespace, &vehicles)?;
+        }
+        Command::Run { .. } | Command::Scenario { .. } => unreachable!("started above"),
+    }
 
+    // Send the last messages before exiting
+    presence.withdraw(&mut client, &namespace)?;
+    if !client.close(CLOSE_TIMEOUT)? {
+        return Err("pending messages not sent before the timeout".into());
+    }
     Ok(())
 }
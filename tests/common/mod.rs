@@ -0,0 +1,311 @@
+//! Test harness with a minimal MQTT 3.1.1 broker running in-process on an ephemeral port.
+//!
+//! The broker supports what the clients use: QoS 0 and 1 publishes, wildcard subscriptions, retained messages and Last Will messages. Sessions can be dropped to simulate a network failure. Everything is forwarded with QoS 0.
+//!
+//! Every publish received from a client is logged in order, so tests can assert on the exact sequence of messages sent to a topic.
+
+#![allow(dead_code)]
+
+use bytes::{Bytes, BytesMut};
+use pc_mqtt_rs::{BrokerConfig, ClientWrapper, Mqtt, Payload};
+use rumqttc::mqttbytes::{
+    self,
+    v4::{
+        self, ConnAck, ConnectReturnCode, LastWill, Packet, PingResp, PubAck, Publish, SubAck,
+        SubscribeReasonCode, UnsubAck,
+    },
+    QoS,
+};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long the wait functions wait before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// A message published to the broker by one of the clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+impl Message {
+    /// Parses the message payload, panicking if it isn't a Payload.
+    pub fn payload(&self) -> Payload {
+        Payload::parse(self.payload.as_bytes())
+            .unwrap_or_else(|e| panic!("{} is not a payload: {}", self.payload, e))
+    }
+}
+
+struct Session {
+    client_id: String,
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: HashMap<usize, Session>,
+    retained: HashMap<String, Bytes>,
+    log: Vec<Message>,
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+/// Handle to a running broker. The broker runs until the test process exits.
+pub struct Broker {
+    port: u16,
+    shared: Shared,
+}
+
+impl Broker {
+    /// Starts a broker listening on 127.0.0.1 with a port chosen by the OS.
+    pub fn start() -> Broker {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind the broker");
+        let port = listener.local_addr().unwrap().port();
+        let shared: Shared = Arc::default();
+
+        let accept_shared = shared.clone();
+        thread::spawn(move || {
+            for (id, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else { continue };
+                let shared = accept_shared.clone();
+                thread::spawn(move || serve(id, stream, shared));
+            }
+        });
+
+        Broker { port, shared }
+    }
+
+    /// Broker settings for the clients under test.
+    pub fn config(&self) -> BrokerConfig {
+        BrokerConfig::new("127.0.0.1", self.port)
+    }
+
+    /// Creates a client connected to the broker, for the test itself to publish with.
+    pub fn client(&self, client_id: &str) -> ClientWrapper {
+        let (client, connection) = Mqtt::new(client_id, &self.config()).unwrap();
+        let _rx = connection.start_loop();
+        client
+    }
+
+    /// Every message published so far on a topic matching filter, in the order the broker received them.
+    pub fn messages(&self, filter: &str) -> Vec<Message> {
+        let state = self.shared.0.lock().unwrap();
+        matching(&state, filter)
+    }
+
+    /// Waits until at least count messages were published on a topic matching filter and returns all of them.
+    pub fn wait_for_messages(&self, filter: &str, count: usize) -> Vec<Message> {
+        self.wait(&format!("{} messages on {}", count, filter), |state| {
+            let messages = matching(state, filter);
+            (messages.len() >= count).then_some(messages)
+        })
+    }
+
+    /// Waits until some client is subscribed to a filter that matches topic.
+    pub fn wait_for_subscriber(&self, topic: &str) {
+        self.wait(&format!("a subscriber to {}", topic), |state| {
+            state
+                .sessions
+                .values()
+                .flat_map(|session| &session.filters)
+                .any(|filter| rumqttc::matches(topic, filter))
+                .then_some(())
+        })
+    }
+
+    /// Closes the connection of a client without a Disconnect packet, as if the network failed, which also publishes its Last Will.
+    pub fn drop_session(&self, client_id: &str) {
+        let state = self.shared.0.lock().unwrap();
+        let session = state
+            .sessions
+            .values()
+            .find(|session| session.client_id == client_id)
+            .unwrap_or_else(|| panic!("{} is not connected", client_id));
+        session.stream.shutdown(Shutdown::Both).unwrap();
+    }
+
+    fn wait<T>(&self, what: &str, mut done: impl FnMut(&State) -> Option<T>) -> T {
+        let (lock, changed) = &*self.shared;
+        let deadline = Instant::now() + TIMEOUT;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if let Some(result) = done(&state) {
+                return result;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                panic!(
+                    "timed out waiting for {}, published so far: {:#?}",
+                    what, state.log
+                );
+            }
+            state = changed.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+}
+
+fn matching(state: &State, filter: &str) -> Vec<Message> {
+    state
+        .log
+        .iter()
+        .filter(|message| rumqttc::matches(&message.topic, filter))
+        .cloned()
+        .collect()
+}
+
+/// Writes a packet to a client, ignoring clients that already went away.
+fn send(
+    stream: &mut TcpStream,
+    write: impl FnOnce(&mut BytesMut) -> Result<usize, mqttbytes::Error>,
+) {
+    let mut buffer = BytesMut::new();
+    write(&mut buffer).expect("failed to encode packet");
+    let _ = stream.write_all(&buffer);
+}
+
+/// Logs a publish, updates the retained messages and forwards it to every matching subscriber.
+fn route(shared: &Shared, topic: &str, payload: Bytes, retain: bool) {
+    let (lock, changed) = &**shared;
+    let mut state = lock.lock().unwrap();
+
+    state.log.push(Message {
+        topic: topic.to_string(),
+        payload: String::from_utf8_lossy(&payload).into_owned(),
+        retain,
+    });
+    if retain {
+        if payload.is_empty() {
+            state.retained.remove(topic);
+        } else {
+            state.retained.insert(topic.to_string(), payload.clone());
+        }
+    }
+
+    for session in state.sessions.values_mut() {
+        if session.filters.iter().any(|f| rumqttc::matches(topic, f)) {
+            let publish = Publish::from_bytes(topic, QoS::AtMostOnce, payload.clone());
+            send(&mut session.stream, |b| publish.write(b));
+        }
+    }
+    changed.notify_all();
+}
+
+/// Handles a single client connection until it closes.
+fn serve(id: usize, mut stream: TcpStream, shared: Shared) {
+    let mut buffer = BytesMut::new();
+    let mut chunk = [0; 4096];
+    let mut last_will: Option<LastWill> = None;
+    let mut clean_disconnect = false;
+
+    loop {
+        let packet = match v4::read(&mut buffer, MAX_PACKET_SIZE) {
+            Ok(packet) => packet,
+            Err(mqttbytes::Error::InsufficientBytes(_)) => match stream.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    continue;
+                }
+            },
+            Err(_) => break,
+        };
+
+        let (lock, changed) = &*shared;
+        match packet {
+            Packet::Connect(connect) => {
+                last_will = connect.last_will;
+                let mut state = lock.lock().unwrap();
+                let mut session = Session {
+                    client_id: connect.client_id,
+                    stream: stream.try_clone().unwrap(),
+                    filters: Vec::new(),
+                };
+                let connack = ConnAck::new(ConnectReturnCode::Success, false);
+                send(&mut session.stream, |b| connack.write(b));
+                state.sessions.insert(id, session);
+            }
+            Packet::Publish(publish) => {
+                if publish.qos != QoS::AtMostOnce {
+                    let mut state = lock.lock().unwrap();
+                    if let Some(session) = state.sessions.get_mut(&id) {
+                        send(&mut session.stream, |b| PubAck::new(publish.pkid).write(b));
+                    }
+                }
+                route(&shared, &publish.topic, publish.payload, publish.retain);
+            }
+            Packet::Subscribe(subscribe) => {
+                let mut state = lock.lock().unwrap();
+                let retained: Vec<(String, Bytes)> = state
+                    .retained
+                    .iter()
+                    .filter(|(topic, _)| {
+                        subscribe
+                            .filters
+                            .iter()
+                            .any(|f| rumqttc::matches(topic, &f.path))
+                    })
+                    .map(|(topic, payload)| (topic.clone(), payload.clone()))
+                    .collect();
+
+                let Some(session) = state.sessions.get_mut(&id) else {
+                    break;
+                };
+                let codes = subscribe
+                    .filters
+                    .iter()
+                    .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                    .collect();
+                send(&mut session.stream, |b| {
+                    SubAck::new(subscribe.pkid, codes).write(b)
+                });
+                for (topic, payload) in retained {
+                    let mut publish = Publish::from_bytes(topic, QoS::AtMostOnce, payload);
+                    publish.retain = true;
+                    send(&mut session.stream, |b| publish.write(b));
+                }
+                // A filter subscribed to again replaces the existing subscription
+                for filter in subscribe.filters {
+                    if !session.filters.contains(&filter.path) {
+                        session.filters.push(filter.path);
+                    }
+                }
+                changed.notify_all();
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                let mut state = lock.lock().unwrap();
+                if let Some(session) = state.sessions.get_mut(&id) {
+                    session.filters.retain(|f| !unsubscribe.topics.contains(f));
+                    send(&mut session.stream, |b| {
+                        UnsubAck::new(unsubscribe.pkid).write(b)
+                    });
+                }
+            }
+            Packet::PingReq => {
+                let mut state = lock.lock().unwrap();
+                if let Some(session) = state.sessions.get_mut(&id) {
+                    send(&mut session.stream, |b| PingResp.write(b));
+                }
+            }
+            Packet::Disconnect => {
+                clean_disconnect = true;
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    shared.0.lock().unwrap().sessions.remove(&id);
+    if let Some(will) = last_will.filter(|_| !clean_disconnect) {
+        route(&shared, &will.topic, will.message, will.retain);
+    }
+}
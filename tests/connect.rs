@@ -0,0 +1,44 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{
+    connect_vehicles, Connecting, Mqtt, Namespace, Payload, Simulator, Topic, TrackLayout,
+};
+use std::time::Duration;
+
+const VEHICLE: &str = "d98ebab7c206";
+const MISSING_VEHICLE: &str = "f4c22c6c0382";
+
+#[test]
+fn vehicles_that_dont_report_are_retried() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    Simulator::new(&[VEHICLE.to_string()], TrackLayout::default())
+        .unwrap()
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&Topic::VehicleI(VEHICLE).get(&ns));
+
+    let (mut client, connection) = Mqtt::new("test_connect", &broker.config()).unwrap();
+    let rx = connection.start_loop();
+    let settings = Connecting {
+        timeout: Duration::from_millis(300),
+        retries: 1,
+    };
+    let vehicles = [VEHICLE.to_string(), MISSING_VEHICLE.to_string()];
+    let results = connect_vehicles(&mut client, &ns, &rx, &vehicles, &settings).unwrap();
+
+    assert_eq!(results[0].id, VEHICLE);
+    assert!(results[0].is_connected());
+    assert_eq!(results[0].attempts, 1);
+    assert_eq!(results[1].id, MISSING_VEHICLE);
+    assert!(!results[1].is_connected());
+    assert_eq!(results[1].attempts, 2);
+
+    let sent: Vec<Payload> = broker
+        .messages(&Topic::VehicleI(MISSING_VEHICLE).get(&ns))
+        .iter()
+        .map(|message| message.payload())
+        .collect();
+    assert_eq!(sent, vec![Payload::Connect(true), Payload::Connect(true)]);
+}
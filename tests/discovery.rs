@@ -0,0 +1,71 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{discover_vehicles, Discovery, Mqtt, Namespace, Payload, Topic};
+use std::time::Duration;
+
+#[test]
+fn discovery_gives_up_when_the_host_never_answers() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let (mut client, connection) = Mqtt::new("test_discover", &broker.config()).unwrap();
+    let rx = connection.start_loop();
+
+    let settings = Discovery {
+        timeout: Duration::from_millis(200),
+        retries: 1,
+        ..Default::default()
+    };
+    let error = discover_vehicles(&mut client, &ns, &rx, &settings).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "the host didn't answer after 2 discover messages"
+    );
+
+    let sent: Vec<Payload> = broker
+        .wait_for_messages(&Topic::HostI.get(&ns), 3)
+        .iter()
+        .map(|message| message.payload())
+        .collect();
+    assert_eq!(
+        sent,
+        vec![
+            Payload::Discover(true),
+            Payload::Discover(true),
+            Payload::Discover(false),
+        ]
+    );
+}
+
+#[test]
+fn discovery_skips_other_messages() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let (mut client, connection) = Mqtt::new("test_discover", &broker.config()).unwrap();
+    let rx = connection.start_loop();
+    let mut host = broker.client("test_host");
+
+    // Unrelated messages arrive on the same receiver before the vehicle list
+    client.subscribe("test/#").unwrap();
+    broker.wait_for_subscriber("test/topic");
+    host.publish("test/topic", "not a vehicle list").unwrap();
+    broker.wait_for_messages("test/topic", 1);
+
+    let list_topic = Topic::HostS("vehicles").get(&ns);
+    let answer = std::thread::spawn(move || {
+        broker.wait_for_messages(&Topic::HostI.get(&ns), 1);
+        host.publish(&list_topic, r#"{"value":["d98ebab7c206"]}"#)
+            .unwrap();
+        broker
+    });
+
+    let settings = Discovery {
+        status_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let vehicles = discover_vehicles(&mut client, &Namespace::default(), &rx, &settings).unwrap();
+    assert_eq!(vehicles.len(), 1);
+    assert_eq!(vehicles[0].id, "d98ebab7c206");
+    assert_eq!(vehicles[0].status, None);
+    answer.join().unwrap();
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{BrokerConfig, Mqtt, Namespace, Presence, PRESENCE_TIMEOUT};
+
+fn instance(broker: &Broker, instance: &str) -> BrokerConfig {
+    let mut config = broker.config();
+    config.instance = Some(instance.to_string());
+    config
+}
+
+#[test]
+fn client_ids_of_running_instances_are_reported() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let running = instance(&broker, "pi");
+    let presence = Presence::new(&running, &["main", "relay"]);
+    let (mut client, connection) = Mqtt::with_last_will(
+        &running.client_id("main"),
+        &running,
+        Some(presence.last_will(&ns)),
+    )
+    .unwrap();
+    let _rx = connection.start_loop();
+    presence.announce(&mut client, &ns).unwrap();
+    broker.wait_for_messages("GroupG/Presence/pi", 1);
+
+    let same = Presence::new(&running, &["main", "speed"]);
+    assert_eq!(
+        same.collisions(&running, &ns, PRESENCE_TIMEOUT).unwrap(),
+        vec!["groupg_main_pi"]
+    );
+    let other = instance(&broker, "laptop");
+    assert!(Presence::new(&other, &["main", "relay"])
+        .collisions(&other, &ns, PRESENCE_TIMEOUT)
+        .unwrap()
+        .is_empty());
+
+    // Once withdrawn, the client IDs can be used again
+    presence.withdraw(&mut client, &ns).unwrap();
+    broker.wait_for_messages("GroupG/Presence/pi", 2);
+    assert!(same
+        .collisions(&running, &ns, PRESENCE_TIMEOUT)
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use common::{Broker, TIMEOUT};
+use pc_mqtt_rs::{ConnectionState, Mqtt};
+
+#[test]
+fn dropped_sessions_reconnect_and_subscribe_again() {
+    let broker = Broker::start();
+    let (mut client, connection) = Mqtt::new("test_reconnect", &broker.config()).unwrap();
+    let (rx, states) = connection.start_loop_with_state();
+    assert_eq!(states.recv_timeout(TIMEOUT), Ok(ConnectionState::Connected));
+    client.subscribe("test/topic").unwrap();
+    broker.wait_for_subscriber("test/topic");
+
+    let mut publisher = broker.client("test_publisher");
+    publisher.publish("test/topic", "before").unwrap();
+    assert_eq!(&rx.recv_timeout(TIMEOUT).unwrap().payload[..], b"before");
+
+    broker.drop_session("test_reconnect");
+    assert_eq!(
+        states.recv_timeout(TIMEOUT),
+        Ok(ConnectionState::Disconnected)
+    );
+    assert_eq!(
+        states.recv_timeout(TIMEOUT),
+        Ok(ConnectionState::Reconnecting(1))
+    );
+    assert_eq!(states.recv_timeout(TIMEOUT), Ok(ConnectionState::Connected));
+
+    // The broker didn't keep the session, so the topic is only received again once the client subscribed again
+    broker.wait_for_subscriber("test/topic");
+    publisher.publish("test/topic", "after").unwrap();
+    assert_eq!(&rx.recv_timeout(TIMEOUT).unwrap().payload[..], b"after");
+}
@@ -0,0 +1,530 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{
+    Namespace, Payload, PolicyConfig, PolicyReport, Rejection, Relay, Report, RuleConfig, Topic,
+};
+
+const VEHICLE: &str = "d98ebab7c206";
+const OTHER_VEHICLE: &str = "f4c22c6c0382";
+
+/// Starts a relay for both vehicles and waits until it is subscribed to all of its topics.
+fn start_relay(broker: &Broker, ns: &Namespace) {
+    let vehicles = [VEHICLE.to_string(), OTHER_VEHICLE.to_string()];
+    Relay::new(&vehicles).run(&broker.config(), ns).unwrap();
+    broker.wait_for_subscriber(&Topic::Relay(&Topic::VehicleI(VEHICLE).get(ns)).get(ns));
+    broker.wait_for_subscriber(&Topic::Emergency.get(ns));
+    broker.wait_for_subscriber(&Topic::Zone.get(ns));
+}
+
+fn relayed(vehicle: &str, ns: &Namespace) -> String {
+    Topic::Relay(&Topic::VehicleI(vehicle).get(ns)).get(ns)
+}
+
+fn payloads(broker: &Broker, topic: &str, count: usize) -> Vec<Payload> {
+    broker
+        .wait_for_messages(topic, count)
+        .iter()
+        .map(|message| message.payload())
+        .collect()
+}
+
+#[test]
+fn emergency_overrides_speed() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    start_relay(&broker, &ns);
+    let mut client = broker.client("test_emergency");
+
+    client
+        .publish(&Topic::Emergency.get(&ns), &Payload::Emergency(true).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(500, 500).get())
+        .unwrap();
+    client
+        .publish(&Topic::Emergency.get(&ns), &Payload::Emergency(false).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(500, 500).get())
+        .unwrap();
+
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 4),
+        vec![
+            Payload::Speed(0, 1000),
+            Payload::Speed(0, 2000),
+            // Releasing restores the last requested speed
+            Payload::Speed(500, 500),
+            Payload::Speed(500, 500),
+        ]
+    );
+    // The emergency stop also reaches vehicles nobody sent a speed to, which have no speed to restore when released
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(OTHER_VEHICLE).get(&ns), 1),
+        vec![Payload::Speed(0, 1000)]
+    );
+    assert_eq!(
+        broker
+            .messages(&Topic::VehicleI(OTHER_VEHICLE).get(&ns))
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn slow_zone_clamps_speed_to_200() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    start_relay(&broker, &ns);
+    let mut client = broker.client("test_zone");
+
+    client
+        .publish(
+            &Topic::Zone.get(&ns),
+            &Payload::Zone200(vec![VEHICLE.to_string()]).get(),
+        )
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(500, 500).get())
+        .unwrap();
+    client
+        .publish(
+            &relayed(OTHER_VEHICLE, &ns),
+            &Payload::Speed(300, 700).get(),
+        )
+        .unwrap();
+    client
+        .publish(&Topic::Zone.get(&ns), &Payload::Zone200(Vec::new()).get())
+        .unwrap();
+
+    // Entering the zone sends nothing before a speed was requested, and leaving it restores the vehicle's own last requested speed
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 2),
+        vec![Payload::Speed(200, 1000), Payload::Speed(500, 500)]
+    );
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(OTHER_VEHICLE).get(&ns), 1),
+        vec![Payload::Speed(300, 700)]
+    );
+}
+
+#[test]
+fn invalid_messages_are_rejected() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    start_relay(&broker, &ns);
+    let mut client = broker.client("test_relay");
+    let status = Topic::Relay(&Topic::VehicleS(VEHICLE).get(&ns)).get(&ns);
+
+    client.publish(&relayed(VEHICLE, &ns), "not json").unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Lights(true, false).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), r#"{"type":"unknown"}"#)
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(5000, 500).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Emergency(true).get())
+        .unwrap();
+    client
+        .publish(&status, &Payload::Speed(300, 500).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(300, 500).get())
+        .unwrap();
+
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 2),
+        vec![Payload::Lights(true, false), Payload::Speed(300, 500)]
+    );
+    let rejections: Vec<Rejection> = broker
+        .wait_for_messages(&Topic::Rejected.get(&ns), 5)
+        .iter()
+        .map(|message| serde_json::from_str(&message.payload).unwrap())
+        .collect();
+    assert_eq!(rejections.len(), 5);
+    assert_eq!(rejections[0].payload, "not json");
+    assert_eq!(
+        rejections[2].reason,
+        "velocity must be between -1200 and 1200"
+    );
+    assert_eq!(rejections[4].topic, status);
+    assert_eq!(rejections[4].reason, "not a vehicle or host command topic");
+}
+
+#[test]
+fn vehicles_and_groups_are_stopped_on_their_own() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string(), OTHER_VEHICLE.to_string()];
+    Relay::new(&vehicles)
+        .with_group("pair", &vehicles)
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&Topic::GroupEmergency("pair").get(&ns));
+    broker.wait_for_subscriber(&Topic::Zone.get(&ns));
+    let mut client = broker.client("test_emergency");
+    let emergency = Payload::Emergency(true).get();
+    let release = Payload::Emergency(false).get();
+    let speed = Payload::Speed(500, 500).get();
+
+    client
+        .publish(&Topic::VehicleEmergency(VEHICLE).get(&ns), &emergency)
+        .unwrap();
+    client.publish(&relayed(VEHICLE, &ns), &speed).unwrap();
+    client
+        .publish(&relayed(OTHER_VEHICLE, &ns), &speed)
+        .unwrap();
+    // Releasing the group keeps the vehicle stopped on its own
+    client
+        .publish(&Topic::GroupEmergency("pair").get(&ns), &emergency)
+        .unwrap();
+    client
+        .publish(&Topic::GroupEmergency("pair").get(&ns), &release)
+        .unwrap();
+    client.publish(&relayed(VEHICLE, &ns), &speed).unwrap();
+    client
+        .publish(&Topic::VehicleEmergency(VEHICLE).get(&ns), &release)
+        .unwrap();
+    client.publish(&relayed(VEHICLE, &ns), &speed).unwrap();
+
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 5),
+        vec![
+            Payload::Speed(0, 1000),
+            Payload::Speed(0, 2000),
+            Payload::Speed(0, 2000),
+            Payload::Speed(500, 500),
+            Payload::Speed(500, 500),
+        ]
+    );
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(OTHER_VEHICLE).get(&ns), 3),
+        vec![
+            Payload::Speed(500, 500),
+            Payload::Speed(0, 1000),
+            Payload::Speed(500, 500),
+        ]
+    );
+}
+
+#[test]
+fn policy_rules_rewrite_and_drop_messages() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string(), OTHER_VEHICLE.to_string()];
+    let policy = PolicyConfig {
+        rules: vec![
+            RuleConfig::MaxSpeed { max: 800 },
+            RuleConfig::LaneLock {
+                vehicles: vec![VEHICLE.to_string()],
+            },
+            RuleConfig::LightsRequired {
+                vehicles: vec![OTHER_VEHICLE.to_string()],
+            },
+        ],
+        report: Report::Changes,
+    };
+    Relay::new(&vehicles)
+        .with_policy(policy.build())
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&relayed(VEHICLE, &ns));
+    let mut client = broker.client("test_policy");
+
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(1000, 500).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Lane(60, 300, 300).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(300, 500).get())
+        .unwrap();
+    // The other vehicle can only drive once its front lights are on
+    client
+        .publish(
+            &relayed(OTHER_VEHICLE, &ns),
+            &Payload::Speed(500, 500).get(),
+        )
+        .unwrap();
+    client
+        .publish(
+            &relayed(OTHER_VEHICLE, &ns),
+            &Payload::Lights(true, false).get(),
+        )
+        .unwrap();
+    client
+        .publish(
+            &relayed(OTHER_VEHICLE, &ns),
+            &Payload::Speed(500, 500).get(),
+        )
+        .unwrap();
+
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 2),
+        vec![Payload::Speed(800, 500), Payload::Speed(300, 500)]
+    );
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(OTHER_VEHICLE).get(&ns), 3),
+        vec![
+            Payload::Speed(0, 500),
+            Payload::Lights(true, false),
+            Payload::Speed(500, 500),
+        ]
+    );
+
+    // Only the rewrites and drops are reported
+    let reports: Vec<PolicyReport> = broker
+        .wait_for_messages(&Topic::Decisions.get(&ns), 3)
+        .iter()
+        .map(|message| serde_json::from_str(&message.payload).unwrap())
+        .collect();
+    let decisions: Vec<(&str, Option<&str>)> = reports
+        .iter()
+        .map(|report| (report.decision.as_str(), report.rule.as_deref()))
+        .collect();
+    assert_eq!(
+        decisions,
+        vec![
+            ("rewrite", Some("max_speed")),
+            ("drop", Some("lane_lock")),
+            ("rewrite", Some("lights_required")),
+        ]
+    );
+    assert_eq!(reports[0].topic, Topic::VehicleI(VEHICLE).get(&ns));
+    assert_eq!(reports[0].payload, Some(Payload::Speed(800, 500)));
+}
+
+#[test]
+fn released_vehicles_restore_their_own_speed_and_lane() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    start_relay(&broker, &ns);
+    let mut client = broker.client("test_restore");
+
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(600, 300).get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Lane(-60, 250, 400).get())
+        .unwrap();
+    client
+        .publish(
+            &relayed(OTHER_VEHICLE, &ns),
+            &Payload::Speed(400, 800).get(),
+        )
+        .unwrap();
+    client
+        .publish(&Topic::Emergency.get(&ns), &Payload::Emergency(true).get())
+        .unwrap();
+    client
+        .publish(&Topic::Emergency.get(&ns), &Payload::Emergency(false).get())
+        .unwrap();
+
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 5),
+        vec![
+            Payload::Speed(600, 300),
+            Payload::Lane(-60, 250, 400),
+            Payload::Speed(0, 1000),
+            Payload::Speed(600, 300),
+            Payload::Lane(-60, 250, 400),
+        ]
+    );
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(OTHER_VEHICLE).get(&ns), 3),
+        vec![
+            Payload::Speed(400, 800),
+            Payload::Speed(0, 1000),
+            Payload::Speed(400, 800),
+        ]
+    );
+}
+
+#[test]
+fn only_listed_vehicles_and_senders_are_relayed() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string(), OTHER_VEHICLE.to_string()];
+    let sender = "groupg_speed_test";
+    Relay::new(&vehicles)
+        .with_senders(&[sender.to_string()], false)
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&relayed(VEHICLE, &ns));
+    let mut client = broker.client("test_senders");
+    let speed = Payload::Speed(300, 500);
+
+    client
+        .publish(&relayed(VEHICLE, &ns), &speed.get())
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &speed.get_from("stray_client"))
+        .unwrap();
+    client
+        .publish(&relayed("ffffffffffff", &ns), &speed.get_from(sender))
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &speed.get_from(sender))
+        .unwrap();
+
+    let reasons: Vec<String> = broker
+        .wait_for_messages(&Topic::Rejected.get(&ns), 3)
+        .iter()
+        .map(|message| {
+            serde_json::from_str::<Rejection>(&message.payload)
+                .unwrap()
+                .reason
+        })
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            "no sender",
+            "unknown sender stray_client",
+            "vehicle ffffffffffff is not in the vehicle list of the relay",
+        ]
+    );
+    // The sender is not passed on to the vehicle
+    let messages = broker.wait_for_messages(&Topic::VehicleI(VEHICLE).get(&ns), 1);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].payload, speed.get());
+    assert!(broker
+        .messages(&Topic::VehicleI("ffffffffffff").get(&ns))
+        .is_empty());
+}
+
+#[test]
+fn emergency_wins_over_slow_zone() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    start_relay(&broker, &ns);
+    let mut client = broker.client("test_emergency_zone");
+
+    client
+        .publish(&Topic::Emergency.get(&ns), &Payload::Emergency(true).get())
+        .unwrap();
+    // Already what the emergency rule sends, so the zone rule must not rewrite it
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(0, 2000).get())
+        .unwrap();
+    client
+        .publish(
+            &Topic::Zone.get(&ns),
+            &Payload::Zone200(vec![VEHICLE.to_string()]).get(),
+        )
+        .unwrap();
+
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 3),
+        vec![
+            Payload::Speed(0, 1000),
+            Payload::Speed(0, 2000),
+            Payload::Speed(0, 2000),
+        ]
+    );
+}
+
+#[test]
+fn zones_only_address_listed_vehicles() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    start_relay(&broker, &ns);
+    let mut client = broker.client("test_zone_allowlist");
+
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(500, 500).get())
+        .unwrap();
+    client
+        .publish(
+            &Topic::Zone.get(&ns),
+            &Payload::Zone200(vec![String::from("ffffffffffff"), VEHICLE.to_string()]).get(),
+        )
+        .unwrap();
+    client
+        .publish(&Topic::Zone.get(&ns), &Payload::Zone200(Vec::new()).get())
+        .unwrap();
+
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 3),
+        vec![
+            Payload::Speed(500, 500),
+            Payload::Speed(200, 1000),
+            Payload::Speed(500, 500),
+        ]
+    );
+    assert!(broker
+        .messages(&Topic::VehicleI("ffffffffffff").get(&ns))
+        .is_empty());
+}
+
+#[test]
+fn retained_release_on_start_sends_nothing() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let mut client = broker.client("test_retained_release");
+    client
+        .publish_retained(&Topic::Emergency.get(&ns), &Payload::Emergency(false).get())
+        .unwrap();
+    broker.wait_for_messages(&Topic::Emergency.get(&ns), 1);
+
+    start_relay(&broker, &ns);
+    // Relayed after the retained release was handled, so it is the first message if nothing was sent
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(500, 500).get())
+        .unwrap();
+
+    let messages = broker.wait_for_messages(&Topic::VehicleI(VEHICLE).get(&ns), 1);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].payload(), Payload::Speed(500, 500));
+    assert!(broker
+        .messages(&Topic::VehicleI(OTHER_VEHICLE).get(&ns))
+        .is_empty());
+}
+
+#[test]
+fn policy_rules_apply_on_top_of_each_other() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string()];
+    let policy = PolicyConfig {
+        rules: vec![
+            RuleConfig::SpeedLimitZone { limit: 200 },
+            RuleConfig::MaxSpeed { max: 150 },
+        ],
+        report: Report::Changes,
+    };
+    Relay::new(&vehicles)
+        .with_policy(policy.build())
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&relayed(VEHICLE, &ns));
+    broker.wait_for_subscriber(&Topic::Zone.get(&ns));
+    let mut client = broker.client("test_policy_chain");
+
+    client
+        .publish(
+            &Topic::Zone.get(&ns),
+            &Payload::Zone200(vec![VEHICLE.to_string()]).get(),
+        )
+        .unwrap();
+    client
+        .publish(&relayed(VEHICLE, &ns), &Payload::Speed(500, 500).get())
+        .unwrap();
+
+    // The zone lowers the velocity to 200, and the lower maximum then to 150
+    assert_eq!(
+        payloads(&broker, &Topic::VehicleI(VEHICLE).get(&ns), 1),
+        vec![Payload::Speed(150, 1000)]
+    );
+    let report: PolicyReport =
+        serde_json::from_str(&broker.wait_for_messages(&Topic::Decisions.get(&ns), 1)[0].payload)
+            .unwrap();
+    assert_eq!(report.decision, "rewrite");
+    assert_eq!(report.rule.as_deref(), Some("max_speed"));
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{shutdown, Blink, Mqtt, Namespace, Payload, Relay, Speed, Topic, Track};
+use std::{thread, time::Duration};
+
+const VEHICLE: &str = "d98ebab7c206";
+
+#[test]
+fn shutdown_stops_the_vehicles_and_every_controller() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let config = broker.config();
+    let vehicles = [VEHICLE.to_string()];
+
+    let relay = Relay::new(&vehicles).run(&config, &ns).unwrap();
+    let track = Track::new(&vehicles, &[20]).run(&config, &ns).unwrap();
+    broker.wait_for_subscriber(&Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns));
+    let speed = Speed::new(&[300], &vehicles)
+        .with_interval(Duration::from_millis(50))
+        .run(&config, &ns)
+        .unwrap();
+    let blink = Blink::new(&vehicles)
+        .with_interval(Duration::from_millis(50))
+        .run(&config, &ns)
+        .unwrap();
+    let vehicle_topic = Topic::VehicleI(VEHICLE).get(&ns);
+    broker.wait_for_messages(&vehicle_topic, 4);
+
+    let (mut client, connection) = Mqtt::new("test_shutdown", &config).unwrap();
+    let _rx = connection.start_loop();
+    shutdown(
+        &mut client,
+        &ns,
+        &vehicles,
+        vec![speed, blink],
+        vec![relay, track],
+        Duration::from_secs(2),
+    )
+    .unwrap();
+
+    // Nothing is sent to the vehicle after it was stopped and disconnected
+    let sent = broker.messages(&vehicle_topic);
+    let last: Vec<Payload> = sent[sent.len() - 2..].iter().map(|m| m.payload()).collect();
+    assert_eq!(last, vec![Payload::Speed(0, 2000), Payload::Connect(false)]);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(broker.messages(&vehicle_topic).len(), sent.len());
+
+    // The relay disconnected cleanly, so its Last Will wasn't published
+    assert!(broker.messages(&Topic::Emergency.get(&ns)).is_empty());
+}
+
+#[test]
+fn relay_last_will_stops_the_fleet_when_it_dies() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let config = broker.config();
+    let vehicles = [VEHICLE.to_string()];
+
+    let _relay = Relay::new(&vehicles).run(&config, &ns).unwrap();
+    broker.wait_for_subscriber(&Topic::Emergency.get(&ns));
+    broker.drop_session(&config.client_id("relay"));
+
+    let messages = broker.wait_for_messages(&Topic::Emergency.get(&ns), 1);
+    assert_eq!(messages[0].payload(), Payload::Emergency(true));
+    assert!(messages[0].retain);
+    // The relay reconnects and gets the retained stop, like any other relay would
+    assert_eq!(
+        broker.wait_for_messages(&Topic::VehicleI(VEHICLE).get(&ns), 1)[0].payload(),
+        Payload::Speed(0, 1000)
+    );
+}
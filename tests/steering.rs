@@ -0,0 +1,299 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{
+    connect_vehicles, discover_vehicles, Blink, Connecting, Discovery, Lane, LaneSchedule,
+    LaneStep, LightMode, LightPattern, Mqtt, Namespace, Payload, Relay, Simulator, Speed,
+    SpeedProgram, SpeedStep, Topic, TrackLayout,
+};
+use std::{thread, time::Duration};
+
+const VEHICLE: &str = "d98ebab7c206";
+
+#[test]
+fn steering_messages_are_relayed_to_the_vehicle() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string()];
+    Relay::new(&vehicles).run(&broker.config(), &ns).unwrap();
+    broker.wait_for_subscriber(&Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns));
+
+    Speed::new(&[300], &vehicles)
+        .run(&broker.config(), &ns)
+        .unwrap();
+    Lane::new(&[-20], &vehicles)
+        .run(&broker.config(), &ns)
+        .unwrap();
+    Blink::new(&vehicles).run(&broker.config(), &ns).unwrap();
+
+    // Each client publishes once right away, in no particular order between clients
+    let mut payloads: Vec<Payload> = broker
+        .wait_for_messages(&Topic::VehicleI(VEHICLE).get(&ns), 3)
+        .iter()
+        .take(3)
+        .map(|message| message.payload())
+        .collect();
+    payloads.sort_by_key(|payload| format!("{:?}", payload));
+    assert_eq!(
+        payloads,
+        vec![
+            Payload::Lane(-20, 200, 500),
+            Payload::Lights(true, true),
+            Payload::Speed(300, 500),
+        ]
+    );
+}
+
+#[test]
+fn simulated_vehicles_are_discovered_and_connected() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string()];
+    Simulator::new(&vehicles, TrackLayout::default())
+        .unwrap()
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&Topic::HostI.get(&ns));
+    broker.wait_for_subscriber(&Topic::VehicleI(VEHICLE).get(&ns));
+
+    let (mut client, connection) = Mqtt::new("test_discover", &broker.config()).unwrap();
+    let rx = connection.start_loop();
+    let discovered = discover_vehicles(&mut client, &ns, &rx, &Discovery::default()).unwrap();
+    assert_eq!(discovered.len(), 1);
+    assert_eq!(discovered[0].id, VEHICLE);
+    assert!(!discovered[0].status.as_ref().unwrap().connected);
+
+    // No relay is needed, the vehicles are connected directly
+    let connected =
+        connect_vehicles(&mut client, &ns, &rx, &vehicles, &Connecting::default()).unwrap();
+    assert_eq!(connected.len(), 1);
+    assert_eq!(connected[0].attempts, 1);
+    assert!(connected[0].status.as_ref().unwrap().connected);
+}
+
+#[test]
+fn vehicles_follow_their_own_speed_program() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let other = "e5a3b1d2c4f6";
+    let vehicles = [VEHICLE.to_string(), other.to_string()];
+    let mut program = SpeedProgram::new(vec![
+        SpeedStep::hold(100, Duration::from_millis(100), 300),
+        SpeedStep::Ramp {
+            from: 200,
+            to: 400,
+            duration_ms: 300,
+            acceleration: 500,
+        },
+    ]);
+    program.update_ms = 100;
+    program.repeat = false;
+    let speed = Speed::new(&[300], &vehicles)
+        .with_program(VEHICLE, program)
+        .run(&broker.config(), &ns)
+        .unwrap();
+
+    let topic = Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns);
+    broker.wait_for_messages(&topic, 2);
+    // The program is over after 400 ms, and nothing is sent anymore
+    thread::sleep(Duration::from_millis(800));
+    let payloads: Vec<Payload> = broker
+        .messages(&topic)
+        .iter()
+        .map(|message| message.payload())
+        .collect();
+    assert_eq!(payloads[0], Payload::Speed(100, 300));
+    // The ramp starts at 200, plus what it went up since the tick that started it
+    assert!(matches!(payloads[1], Payload::Speed(200..=250, 500)));
+    assert_eq!(payloads.last(), Some(&Payload::Speed(400, 500)));
+    for payload in &payloads[1..] {
+        assert!(matches!(payload, Payload::Speed(200..=400, 500)));
+    }
+    speed.stop_and_join().unwrap();
+    assert_eq!(broker.messages(&topic).len(), payloads.len());
+
+    // The other vehicle still follows the velocity list
+    let other_topic = Topic::Relay(&Topic::VehicleI(other).get(&ns)).get(&ns);
+    assert_eq!(
+        broker.messages(&other_topic)[0].payload(),
+        Payload::Speed(300, 500)
+    );
+}
+
+#[test]
+fn a_finished_program_ends_at_its_last_velocity() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string()];
+    // The last hold is over before the tick after it starts
+    let mut program = SpeedProgram::new(vec![
+        SpeedStep::hold(300, Duration::from_millis(175), 500),
+        SpeedStep::hold(100, Duration::from_millis(1), 500),
+    ]);
+    program.repeat = false;
+    let speed = Speed::new(&[300], &vehicles)
+        .with_program(VEHICLE, program)
+        .run(&broker.config(), &ns)
+        .unwrap();
+
+    let topic = Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns);
+    let payloads: Vec<Payload> = broker
+        .wait_for_messages(&topic, 2)
+        .iter()
+        .map(|message| message.payload())
+        .collect();
+    assert_eq!(
+        payloads,
+        vec![Payload::Speed(300, 500), Payload::Speed(100, 500)]
+    );
+    speed.stop_and_join().unwrap();
+}
+
+#[test]
+fn lane_changes_follow_tracks_and_phase_shifts() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let other = "e5a3b1d2c4f6";
+    let vehicles = [VEHICLE.to_string(), other.to_string()];
+    let mut overtake = LaneSchedule::new(vec![
+        LaneStep {
+            on_track: Some(20),
+            duration_ms: 100,
+            ..LaneStep::timed(-60, 400, 1000, Duration::ZERO)
+        },
+        LaneStep::timed(60, 200, 500, Duration::ZERO),
+    ]);
+    overtake.repeat = false;
+    let mut shifted =
+        LaneSchedule::new(vec![LaneStep::timed(30, 200, 500, Duration::from_secs(5))]);
+    shifted.phase_ms = 400;
+    Lane::new(&[0], &vehicles)
+        .with_schedule(VEHICLE, overtake)
+        .with_schedule(other, shifted)
+        .run(&broker.config(), &ns)
+        .unwrap();
+    let track_topic = Topic::VehicleE(VEHICLE, "track").get(&ns);
+    broker.wait_for_subscriber(&track_topic);
+
+    let mut vehicle = broker.client("test_vehicle");
+    vehicle.publish(&track_topic, r#"{"trackId":17}"#).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let topic = Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns);
+    let other_topic = Topic::Relay(&Topic::VehicleI(other).get(&ns)).get(&ns);
+    assert!(broker.messages(&topic).is_empty());
+    assert!(broker.messages(&other_topic).is_empty());
+
+    // Entering track 20 starts the overtake, and the second lane change follows 100 ms later
+    vehicle.publish(&track_topic, r#"{"trackId":20}"#).unwrap();
+    let payloads: Vec<Payload> = broker
+        .wait_for_messages(&topic, 2)
+        .iter()
+        .map(|message| message.payload())
+        .collect();
+    assert_eq!(
+        payloads,
+        vec![Payload::Lane(-60, 400, 1000), Payload::Lane(60, 200, 500)]
+    );
+    assert_eq!(
+        broker.wait_for_messages(&other_topic, 1)[0].payload(),
+        Payload::Lane(30, 200, 500)
+    );
+}
+
+#[test]
+fn lights_alternate_between_vehicles_and_follow_the_zone() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let other = "e5a3b1d2c4f6";
+    let vehicles = [VEHICLE.to_string(), other.to_string()];
+    Blink::new(&vehicles)
+        .with_pattern(LightPattern::alternate(Duration::from_millis(200)))
+        .with_alternate(true)
+        .with_zone_pattern(LightPattern {
+            front: LightMode::On,
+            back: LightMode::On,
+        })
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&Topic::Zone.get(&ns));
+
+    let topic = Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns);
+    let other_topic = Topic::Relay(&Topic::VehicleI(other).get(&ns)).get(&ns);
+    assert_eq!(
+        broker.wait_for_messages(&topic, 1)[0].payload(),
+        Payload::Lights(true, false)
+    );
+    assert_eq!(
+        broker.wait_for_messages(&other_topic, 1)[0].payload(),
+        Payload::Lights(false, true)
+    );
+
+    // Inside the slow zone both lights stay on, so nothing is sent after the change
+    let mut client = broker.client("test_zone");
+    client
+        .publish(
+            &Topic::Zone.get(&ns),
+            &Payload::Zone200(vec![VEHICLE.to_string()]).get(),
+        )
+        .unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let count = broker.messages(&topic).len();
+    assert_eq!(
+        broker.messages(&topic).last().unwrap().payload(),
+        Payload::Lights(true, true)
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(broker.messages(&topic).len(), count);
+    assert!(broker.messages(&other_topic).len() > 2);
+}
+
+#[test]
+fn lights_follow_vehicle_and_group_emergencies() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let other = "e5a3b1d2c4f6";
+    let vehicles = [VEHICLE.to_string(), other.to_string()];
+    Blink::new(&vehicles)
+        .with_pattern(LightPattern {
+            front: LightMode::Off,
+            back: LightMode::Off,
+        })
+        .with_emergency_pattern(LightPattern {
+            front: LightMode::On,
+            back: LightMode::On,
+        })
+        .with_group("pair", &[other.to_string()])
+        .run(&broker.config(), &ns)
+        .unwrap();
+    broker.wait_for_subscriber(&Topic::GroupEmergency("+").get(&ns));
+
+    let topic = Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns);
+    let other_topic = Topic::Relay(&Topic::VehicleI(other).get(&ns)).get(&ns);
+    broker.wait_for_messages(&topic, 1);
+    broker.wait_for_messages(&other_topic, 1);
+
+    // Only the vehicle stopped on its own shows the emergency pattern
+    let mut client = broker.client("test_emergency");
+    client
+        .publish(
+            &Topic::VehicleEmergency(VEHICLE).get(&ns),
+            &Payload::Emergency(true).get(),
+        )
+        .unwrap();
+    assert_eq!(
+        broker.wait_for_messages(&topic, 2)[1].payload(),
+        Payload::Lights(true, true)
+    );
+    assert_eq!(broker.messages(&other_topic).len(), 1);
+
+    client
+        .publish(
+            &Topic::GroupEmergency("pair").get(&ns),
+            &Payload::Emergency(true).get(),
+        )
+        .unwrap();
+    assert_eq!(
+        broker.wait_for_messages(&other_topic, 2)[1].payload(),
+        Payload::Lights(true, true)
+    );
+}
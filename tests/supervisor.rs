@@ -0,0 +1,116 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{Context, Controller, Error, Namespace, Supervisor};
+use rumqttc::Publish;
+use std::time::Duration;
+
+/// Echoes every message on "test/in" to "test/out", and panics on "panic".
+#[derive(Clone)]
+struct Echo {
+    ticks: u32,
+}
+
+impl Controller for Echo {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn subscriptions(&self, _ns: &Namespace) -> Vec<String> {
+        vec![String::from("test/in")]
+    }
+
+    fn on_message(&mut self, ctx: &mut Context, message: &Publish) -> Result<(), Error> {
+        let payload = String::from_utf8_lossy(&message.payload).to_string();
+        if payload == "panic" {
+            panic!("asked to panic");
+        }
+        ctx.client.publish("test/out", &payload)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(50))
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context) -> Result<(), Error> {
+        self.ticks += 1;
+        // Only the first tick after each start is published
+        if self.ticks == 1 {
+            ctx.client.publish("test/tick", "started")?;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self, ctx: &mut Context) -> Result<(), Error> {
+        ctx.client.publish("test/out", "bye")
+    }
+}
+
+#[test]
+fn custom_controllers_get_messages_and_ticks() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let mut supervisor = Supervisor::new(&broker.config(), &ns);
+    supervisor.add(Echo { ticks: 0 }).unwrap();
+    broker.wait_for_subscriber("test/in");
+    assert_eq!(broker.wait_for_messages("test/tick", 1).len(), 1);
+
+    let mut client = broker.client("test_supervisor");
+    client.publish("test/in", "hello").unwrap();
+    broker.wait_for_messages("test/out", 1);
+    assert_eq!(supervisor.running(), vec!["echo"]);
+
+    let mut main = broker.client("test_main");
+    supervisor
+        .shutdown(&mut main, &[], Duration::from_secs(2))
+        .unwrap();
+    let payloads: Vec<String> = broker
+        .messages("test/out")
+        .into_iter()
+        .map(|message| message.payload)
+        .collect();
+    assert_eq!(payloads, vec!["hello", "bye"]);
+}
+
+#[test]
+fn panicking_controllers_are_restarted() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let mut supervisor = Supervisor::new(&broker.config(), &ns);
+    supervisor.add(Echo { ticks: 0 }).unwrap();
+    broker.wait_for_subscriber("test/in");
+    broker.wait_for_messages("test/tick", 1);
+
+    let mut client = broker.client("test_supervisor");
+    client.publish("test/in", "panic").unwrap();
+    // The fresh copy ticks again and keeps the subscription of the connection
+    assert_eq!(broker.wait_for_messages("test/tick", 2).len(), 2);
+    client.publish("test/in", "still there").unwrap();
+    assert_eq!(
+        broker.wait_for_messages("test/out", 1)[0].payload,
+        "still there"
+    );
+    assert_eq!(supervisor.running(), vec!["echo"]);
+}
+
+#[test]
+fn restarted_controllers_get_the_retained_messages_again() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let mut client = broker.client("test_supervisor");
+    client.publish_retained("test/in", "remembered").unwrap();
+    broker.wait_for_messages("test/in", 1);
+
+    let mut supervisor = Supervisor::new(&broker.config(), &ns);
+    supervisor.add(Echo { ticks: 0 }).unwrap();
+    broker.wait_for_messages("test/out", 1);
+
+    client.publish("test/in", "panic").unwrap();
+    // The fresh copy starts from the retained state, like a relay under an emergency stop
+    let payloads: Vec<String> = broker
+        .wait_for_messages("test/out", 2)
+        .into_iter()
+        .map(|message| message.payload)
+        .collect();
+    assert_eq!(payloads, vec!["remembered", "remembered"]);
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::Broker;
+use pc_mqtt_rs::{Namespace, Payload, Relay, Topic, Track, TrackEvent};
+
+const VEHICLE: &str = "d98ebab7c206";
+
+fn track_event(track_id: u64) -> String {
+    serde_json::to_string(&TrackEvent { track_id }).unwrap()
+}
+
+#[test]
+fn slow_tracks_update_the_zone() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    Track::new(&[VEHICLE.to_string()], &[20, 4])
+        .run(&broker.config(), &ns)
+        .unwrap();
+    let track_topic = Topic::VehicleE(VEHICLE, "track").get(&ns);
+    broker.wait_for_subscriber(&track_topic);
+
+    let mut client = broker.client("test_track");
+    for track_id in [33, 20, 4, 21] {
+        client
+            .publish(&track_topic, &track_event(track_id))
+            .unwrap();
+    }
+
+    let zone: Vec<Payload> = broker
+        .wait_for_messages(&Topic::Zone.get(&ns), 2)
+        .iter()
+        .map(|message| message.payload())
+        .collect();
+    assert_eq!(
+        zone,
+        vec![
+            Payload::Zone200(vec![VEHICLE.to_string()]),
+            Payload::Zone200(Vec::new()),
+        ]
+    );
+}
+
+#[test]
+fn vehicles_on_slow_tracks_are_slowed_down_by_the_relay() {
+    let broker = Broker::start();
+    let ns = Namespace::default();
+    let vehicles = [VEHICLE.to_string()];
+    Relay::new(&vehicles).run(&broker.config(), &ns).unwrap();
+    Track::new(&vehicles, &[20])
+        .run(&broker.config(), &ns)
+        .unwrap();
+    let track_topic = Topic::VehicleE(VEHICLE, "track").get(&ns);
+    broker.wait_for_subscriber(&track_topic);
+    broker.wait_for_subscriber(&Topic::Zone.get(&ns));
+
+    let relayed = Topic::Relay(&Topic::VehicleI(VEHICLE).get(&ns)).get(&ns);
+    broker.wait_for_subscriber(&relayed);
+
+    let mut client = broker.client("test_track_relay");
+    // The relay only slows down vehicles whose speed it knows
+    client
+        .publish(&relayed, &Payload::Speed(500, 500).get())
+        .unwrap();
+    client.publish(&track_topic, &track_event(20)).unwrap();
+
+    let messages = broker.wait_for_messages(&Topic::VehicleI(VEHICLE).get(&ns), 2);
+    assert_eq!(messages[1].payload(), Payload::Speed(200, 1000));
+}